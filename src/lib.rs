@@ -1,22 +1,196 @@
+#[cfg(all(feature = "upload", not(feature = "flate2")))]
 use deflate::deflate_bytes_gzip;
+#[cfg(feature = "upload")]
+use flate2::read::GzDecoder;
+#[cfg(feature = "flate2")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "upload")]
 use reqwest::{
     blocking::{
         multipart::{Form, Part},
-        Client,
+        Client, Response,
     },
-    StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER},
+    Method, Url,
 };
 use serde::{
-    ser::{SerializeStruct, Serializer},
+    ser::{SerializeMap, Serializer},
     Deserialize, Serialize,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::var;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+#[cfg(feature = "upload")]
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+#[cfg(feature = "upload")]
+use std::time::{Duration, Instant};
+
+/// Default content type used for the gzipped report sent to coveralls
+pub const DEFAULT_CONTENT_TYPE: &str = "gzip/json";
+
+/// Commit messages read by `GitInfo::from_env` are truncated to this many
+/// characters
+pub const DEFAULT_MAX_MESSAGE_CHARS: usize = 4096;
+
+/// Hits on lines past the end of a file only extend its coverage up to this
+/// many lines, so a bogus line number from corrupt input can't exhaust memory
+const MAX_EXPANDED_LINES: usize = 1 << 22;
+
+/// Content type used for reports sent without compression
+#[cfg(feature = "upload")]
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Serialized reports smaller than this are sent uncompressed with
+/// `Compression::Auto` as the gzip overhead outweighs the saving
+#[cfg(feature = "upload")]
+const AUTO_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Responses from the server are truncated to this size by the default
+/// transport unless changed with `set_max_response_bytes`
+#[cfg(feature = "upload")]
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// Longest `send_with_retries` waits between attempts unless changed with
+/// `set_max_retry_wait`, however long the server's `Retry-After` asks for
+#[cfg(feature = "upload")]
+pub const DEFAULT_MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Errors that can occur when creating or uploading a coveralls report
+#[derive(Debug)]
+pub enum CoverallsError {
+    /// Error reading or writing files
+    Io(io::Error),
+    /// Error serializing or deserializing JSON
+    Json(serde_json::Error),
+    /// Error from the HTTP client
+    #[cfg(feature = "upload")]
+    Http(reqwest::Error),
+    /// A parallel build was requested but there's no service number to group
+    /// the jobs by
+    MissingServiceNumber,
+    /// A custom HTTP header has an invalid name or value
+    InvalidHeader(String),
+    /// A host resolve override has an invalid IP address
+    InvalidAddress(String),
+    /// A pull request isn't a number
+    InvalidPullRequest(String),
+    /// An endpoint URL couldn't be parsed
+    InvalidUrl(String),
+    /// The server rejected the upload, includes the HTTP status code and the
+    /// message from the server if it gave one
+    UploadFailed(u32, Option<String>),
+    /// The compressed payload doesn't decompress back to the report
+    CorruptPayload,
+    /// The upload hasn't completed, includes the pending or unknown status
+    UploadIncomplete(UploadStatus),
+    /// Several jobs of a parallel build have the same service job ID
+    DuplicateJob(String),
+    /// The crate was built without a TLS feature so HTTPS uploads can't work
+    TlsUnsupported,
+    /// GitHub Actions didn't give an OIDC token, includes the HTTP status code
+    /// and the message from the server if it gave one
+    OidcTokenFailed(u32, Option<String>),
+    /// Invalid glob pattern
+    #[cfg(feature = "glob")]
+    Pattern(glob::PatternError),
+}
+
+impl fmt::Display for CoverallsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoverallsError::Io(ref e) => write!(f, "IO error: {}", e),
+            CoverallsError::Json(ref e) => write!(f, "JSON error: {}", e),
+            #[cfg(feature = "upload")]
+            CoverallsError::Http(ref e) => write!(f, "HTTP error: {}", e),
+            CoverallsError::MissingServiceNumber => {
+                write!(f, "parallel builds require a service number")
+            }
+            CoverallsError::InvalidHeader(ref h) => write!(f, "invalid HTTP header: {}", h),
+            CoverallsError::InvalidAddress(ref a) => write!(f, "invalid IP address: {}", a),
+            CoverallsError::InvalidPullRequest(ref pr) => {
+                write!(f, "pull request isn't a number: {}", pr)
+            }
+            CoverallsError::InvalidUrl(ref url) => write!(f, "invalid URL: {}", url),
+            CoverallsError::UploadFailed(code, Some(ref msg)) => {
+                write!(f, "upload failed with HTTP {}: {}", code, msg)
+            }
+            CoverallsError::UploadFailed(code, None) => {
+                write!(f, "upload failed with HTTP {}", code)
+            }
+            CoverallsError::CorruptPayload => {
+                write!(f, "compressed payload doesn't match the report")
+            }
+            CoverallsError::UploadIncomplete(status) => {
+                write!(f, "upload didn't complete: {:?}", status)
+            }
+            CoverallsError::DuplicateJob(ref id) => {
+                write!(f, "duplicate job ID in parallel build: {}", id)
+            }
+            CoverallsError::TlsUnsupported => write!(
+                f,
+                "HTTPS isn't supported, enable the rustls-tls or native-tls feature"
+            ),
+            CoverallsError::OidcTokenFailed(code, Some(ref msg)) => {
+                write!(
+                    f,
+                    "requesting the OIDC token failed with HTTP {}: {}",
+                    code, msg
+                )
+            }
+            CoverallsError::OidcTokenFailed(code, None) => {
+                write!(f, "requesting the OIDC token failed with HTTP {}", code)
+            }
+            #[cfg(feature = "glob")]
+            CoverallsError::Pattern(ref e) => write!(f, "invalid glob pattern: {}", e),
+        }
+    }
+}
+
+impl Error for CoverallsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CoverallsError::Io(ref e) => Some(e),
+            CoverallsError::Json(ref e) => Some(e),
+            #[cfg(feature = "upload")]
+            CoverallsError::Http(ref e) => Some(e),
+            #[cfg(feature = "glob")]
+            CoverallsError::Pattern(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CoverallsError {
+    fn from(e: io::Error) -> Self {
+        CoverallsError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for CoverallsError {
+    fn from(e: serde_json::Error) -> Self {
+        CoverallsError::Json(e)
+    }
+}
+
+#[cfg(feature = "upload")]
+impl From<reqwest::Error> for CoverallsError {
+    fn from(e: reqwest::Error) -> Self {
+        CoverallsError::Http(e)
+    }
+}
+
+#[cfg(feature = "glob")]
+impl From<glob::PatternError> for CoverallsError {
+    fn from(e: glob::PatternError) -> Self {
+        CoverallsError::Pattern(e)
+    }
+}
 
 /// Representation of branch data
 #[derive(
@@ -29,8 +203,69 @@ pub struct BranchData {
     pub hits: usize,
 }
 
+impl BranchData {
+    /// Converts the flat array format used by coveralls back into branch
+    /// data. Any trailing values not forming a complete branch are ignored.
+    pub fn from_flat(flat: &[usize]) -> Vec<BranchData> {
+        flat.chunks_exact(4)
+            .map(|x| BranchData {
+                line_number: x[0],
+                block_name: x[1],
+                branch_number: x[2],
+                hits: x[3],
+            })
+            .collect()
+    }
+
+    /// Creates branch data from cobertura style condition coverage, e.g.
+    /// `condition-coverage="50% (1/2)"` is `covered = 1` and `total = 2`.
+    /// Cobertura doesn't record which branches were taken, so the first
+    /// `covered` branches are given one hit and the rest none.
+    pub fn from_cobertura(line: usize, total: usize, covered: usize) -> Vec<BranchData> {
+        (0..total)
+            .map(|branch| BranchData {
+                line_number: line,
+                block_name: 0,
+                branch_number: branch,
+                hits: (branch < covered) as usize,
+            })
+            .collect()
+    }
+}
+
+/// Merges the line hits of several runs into one map, summing the hits of
+/// lines present in more than one map. Sums saturate instead of overflowing.
+pub fn merge_line_hits(maps: &[&HashMap<usize, usize>]) -> HashMap<usize, usize> {
+    let mut merged = HashMap::new();
+    for (line, hits) in maps.iter().flat_map(|m| m.iter()) {
+        let total = merged.entry(*line).or_insert(0usize);
+        *total = total.saturating_add(*hits);
+    }
+    merged
+}
+
+/// Finds the root of the git repository containing `start` by walking up to
+/// the nearest directory with a `.git` entry, for use as the repository root
+/// with `Source::new_relative`. A `.git` file, as used by work trees and
+/// submodules, also marks the root.
+pub fn find_repo_root(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
 /// Expands the line map into the form expected by coveralls (includes uncoverable lines)
+/// The length is extended to cover hits past `line_count` so none are dropped,
+/// except those on lines past `MAX_EXPANDED_LINES` which are ignored
 fn expand_lines(lines: &HashMap<usize, usize>, line_count: usize) -> Vec<Option<usize>> {
+    let line_count = lines
+        .keys()
+        .copied()
+        .filter(|&l| l <= MAX_EXPANDED_LINES)
+        .max()
+        .unwrap_or(0)
+        .max(line_count);
     (0..line_count)
         .map(|x| match lines.get(&(x + 1)) {
             Some(x) => Some(*x),
@@ -48,13 +283,101 @@ fn expand_branches(branches: &Vec<BranchData>) -> Vec<usize> {
         .collect::<Vec<usize>>()
 }
 
+/// Hash algorithms for digests of source files. Coveralls only accepts MD5
+/// for the uploaded `source_digest`, the others are for local use such as
+/// caching or diffing reports.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum DigestAlgorithm {
+    /// MD5 as used by coveralls
+    #[default]
+    Md5,
+    /// BLAKE3, much faster than MD5 but never uploaded
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    /// Computes the digest of `data` as a hex string
+    pub fn digest(&self, data: &[u8]) -> String {
+        match *self {
+            DigestAlgorithm::Md5 => hex_digest::to_hex(&md5::compute(data).0),
+            #[cfg(feature = "blake3")]
+            DigestAlgorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+        }
+    }
+}
+
+/// Serialization of MD5 digests as the hex strings coveralls expects
+mod hex_digest {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn to_hex(digest: &[u8]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn serialize<S>(digest: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&to_hex(digest))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 16], D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() != 32 || !hex.is_ascii() {
+            return Err(D::Error::custom(format!("invalid MD5 digest: {}", hex)));
+        }
+        let mut digest = [0u8; 16];
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .map_err(|_| D::Error::custom(format!("invalid MD5 digest: {}", hex)))?;
+        }
+        Ok(digest)
+    }
+}
+
+/// Reads the payload of the GitHub Actions event that triggered the workflow
+/// from `GITHUB_EVENT_PATH`, null if it's missing or invalid
+fn github_event() -> serde_json::Value {
+    var("GITHUB_EVENT_PATH")
+        .ok()
+        .and_then(|p| std::fs::read(p).ok())
+        .and_then(|b| serde_json::from_slice::<serde_json::Value>(&b).ok())
+        .unwrap_or_default()
+}
+
+/// Resolves any symlinks in the directories of a path while keeping the file
+/// name as is
+fn resolve_links(path: &Path) -> Result<PathBuf, io::Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            Ok(parent.canonicalize()?.join(name))
+        }
+        _ => path.canonicalize(),
+    }
+}
+
+/// A file to create a source for as (repo path, absolute path, line hits,
+/// branches), the arguments of `Source::new`
+#[cfg(feature = "rayon")]
+pub type SourceFile = (
+    PathBuf,
+    PathBuf,
+    HashMap<usize, usize>,
+    Option<Vec<BranchData>>,
+);
+
 /// Struct representing source files and the coverage for coveralls
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize, Serialize)]
 pub struct Source {
     /// Name of the source file. Represented as path relative to root of repo
     name: String,
-    /// MD5 hash of the source file
-    source_digest: String,
+    /// MD5 hash of the source file, serialized as a hex string
+    #[serde(with = "hex_digest")]
+    source_digest: [u8; 16],
     /// Coverage for the source. Each element is a line with the following rules:
     /// None - not relevant to coverage
     /// 0 - not covered
@@ -66,6 +389,9 @@ pub struct Source {
     /// Contents of the source file (Manual Repos on Enterprise only)
     #[serde(skip_serializing_if = "Option::is_none")]
     source: Option<String>,
+    /// Optional note on the source, e.g. why lines were excluded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
 }
 
 impl Source {
@@ -81,10 +407,62 @@ impl Source {
         lines: &HashMap<usize, usize>,
         branches: &Option<Vec<BranchData>>,
         include_source: bool,
+    ) -> Result<Source, io::Error> {
+        Source::from_file(repo_path, path, lines, branches, include_source, None)
+    }
+
+    /// Creates a source description like `new` but with the number of lines
+    /// given instead of counted from the file, for tools working on transformed
+    /// sources. The coverage is still extended to include hits past the end.
+    pub fn new_with_line_count(
+        repo_path: &Path,
+        path: &Path,
+        lines: &HashMap<usize, usize>,
+        branches: &Option<Vec<BranchData>>,
+        include_source: bool,
+        line_count: usize,
+    ) -> Result<Source, io::Error> {
+        Source::from_file(
+            repo_path,
+            path,
+            lines,
+            branches,
+            include_source,
+            Some(line_count),
+        )
+    }
+
+    fn from_file(
+        repo_path: &Path,
+        path: &Path,
+        lines: &HashMap<usize, usize>,
+        branches: &Option<Vec<BranchData>>,
+        include_source: bool,
+        line_count: Option<usize>,
     ) -> Result<Source, io::Error> {
         let mut code = File::open(path)?;
         let mut content = String::new();
         code.read_to_string(&mut content)?;
+        Ok(Source::from_content(
+            repo_path,
+            md5::compute(&content).0,
+            content,
+            lines,
+            branches,
+            include_source,
+            line_count,
+        ))
+    }
+
+    fn from_content(
+        repo_path: &Path,
+        source_digest: [u8; 16],
+        content: String,
+        lines: &HashMap<usize, usize>,
+        branches: &Option<Vec<BranchData>>,
+        include_source: bool,
+        line_count: Option<usize>,
+    ) -> Source {
         let src = if include_source {
             Some(content.clone())
         } else {
@@ -95,14 +473,247 @@ impl Source {
             &Some(ref b) => Some(expand_branches(&b)),
             &None => None,
         };
-        let line_count = content.lines().count();
-        Ok(Source {
+        let line_count = line_count.unwrap_or_else(|| content.lines().count());
+        Source {
             name: repo_path.to_str().unwrap_or("").to_string(),
-            source_digest: format!("{:x}", md5::compute(content)),
+            source_digest,
             coverage: expand_lines(lines, line_count),
             branches: brch,
             source: src,
-        })
+            comment: None,
+        }
+    }
+
+    /// Creates a source description like `new` for a file that isn't UTF-8,
+    /// decoding it with the given `encoding`. Malformed sequences are replaced
+    /// and the digest is computed from the file as it is on disk.
+    #[cfg(feature = "encoding")]
+    pub fn new_with_encoding(
+        repo_path: &Path,
+        path: &Path,
+        lines: &HashMap<usize, usize>,
+        branches: &Option<Vec<BranchData>>,
+        include_source: bool,
+        encoding: &'static encoding_rs::Encoding,
+    ) -> Result<Source, io::Error> {
+        let raw = std::fs::read(path)?;
+        let (content, _, _) = encoding.decode(&raw);
+        Ok(Source::from_content(
+            repo_path,
+            md5::compute(&raw).0,
+            content.into_owned(),
+            lines,
+            branches,
+            include_source,
+            None,
+        ))
+    }
+
+    /// Name of the source file, the path relative to the repository root
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// MD5 digest of the source file as a hex string
+    pub fn digest(&self) -> String {
+        hex_digest::to_hex(&self.source_digest)
+    }
+
+    /// MD5 digest of the source file as raw bytes
+    pub fn digest_bytes(&self) -> Vec<u8> {
+        self.source_digest.to_vec()
+    }
+
+    /// Number of lines in the coverage data
+    pub fn line_count(&self) -> usize {
+        self.coverage.len()
+    }
+
+    /// Branch data of the source, if it has any
+    pub fn branch_data(&self) -> Option<Vec<BranchData>> {
+        self.branches.as_ref().map(|b| BranchData::from_flat(b))
+    }
+
+    /// Returns true if the contents of the source file are embedded
+    pub fn has_embedded_source(&self) -> bool {
+        self.source.is_some()
+    }
+
+    /// Sorts the branch data by line, block and branch number and removes
+    /// exact duplicates so coveralls renders the branches cleanly
+    pub fn normalize_branches(&mut self) {
+        if let Some(mut branches) = self.branch_data() {
+            branches.sort();
+            branches.dedup();
+            self.branches = Some(expand_branches(&branches));
+        }
+    }
+
+    /// Percentage of branches taken at least once, `None` if the source has no
+    /// branch data
+    pub fn branch_coverage_percent(&self) -> Option<f64> {
+        let branches = self.branch_data().filter(|b| !b.is_empty())?;
+        let covered = branches.iter().filter(|b| b.hits >= 1).count();
+        Some(100.0 * covered as f64 / branches.len() as f64)
+    }
+
+    /// Returns true if any line in the source has been hit
+    pub fn is_covered(&self) -> bool {
+        self.coverage.iter().any(|x| matches!(x, Some(n) if *n > 0))
+    }
+
+    /// Returns true if any line in the source is relevant to coverage
+    pub fn has_coverage_data(&self) -> bool {
+        self.coverage.iter().any(Option::is_some)
+    }
+
+    /// Iterates over the 1-based line numbers which have been hit
+    pub fn covered_lines(&self) -> impl Iterator<Item = usize> + '_ {
+        self.coverage
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| matches!(x, Some(n) if *n > 0))
+            .map(|(i, _)| i + 1)
+    }
+
+    /// Iterates over the 1-based line numbers which are relevant to coverage
+    /// but haven't been hit
+    pub fn uncovered_lines(&self) -> impl Iterator<Item = usize> + '_ {
+        self.coverage
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x == Some(0))
+            .map(|(i, _)| i + 1)
+    }
+
+    /// Marks the 1-based lines in `range` as not relevant to coverage, lines
+    /// past the end of the source are ignored
+    pub fn exclude_lines(&mut self, range: std::ops::RangeInclusive<usize>) {
+        let start = range.start().saturating_sub(1);
+        let end = (*range.end()).min(self.coverage.len());
+        if start < end {
+            self.coverage[start..end].iter_mut().for_each(|x| *x = None);
+        }
+    }
+
+    /// Records a hit on the 1-based `line`, see `add_hits`
+    pub fn add_hit(&mut self, line: usize) {
+        self.add_hits(line, 1);
+    }
+
+    /// Adds `n` hits to the 1-based `line`, marking it as relevant to
+    /// coverage if it wasn't already. The coverage is extended if the line is
+    /// past the end of it. Line 0 is ignored.
+    pub fn add_hits(&mut self, line: usize, n: usize) {
+        if line == 0 {
+            return;
+        }
+        if self.coverage.len() < line {
+            self.coverage.resize(line, None);
+        }
+        let hits = self.coverage[line - 1].get_or_insert(0);
+        *hits = hits.saturating_add(n);
+    }
+
+    /// Caps the hit count of every line to `max`, some coveralls servers
+    /// reject huge hit counts
+    pub fn clamp_hits(&mut self, max: usize) {
+        for hits in self.coverage.iter_mut().flatten() {
+            *hits = (*hits).min(max);
+        }
+    }
+
+    /// Attaches a comment to the source, only sent when set
+    pub fn set_comment(&mut self, comment: &str) {
+        self.comment = Some(comment.to_string());
+    }
+
+    /// Creates a source description for a file given the repository root and
+    /// the absolute path of the file. The path relative to the repository root
+    /// is computed internally. Returns an error if `abs_path` isn't contained
+    /// in `repo_root`. Symlinked directories are resolved so a file accessed
+    /// through a link to the repository still gets its path in the repository,
+    /// the file name itself is kept even if it's a symlink.
+    pub fn new_relative(
+        repo_root: &Path,
+        abs_path: &Path,
+        lines: &HashMap<usize, usize>,
+        branches: &Option<Vec<BranchData>>,
+        include_source: bool,
+    ) -> Result<Source, io::Error> {
+        let repo_path = match abs_path.strip_prefix(repo_root) {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => resolve_links(abs_path)?
+                .strip_prefix(repo_root.canonicalize()?)
+                .map(Path::to_path_buf)
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} is not in repository {}",
+                            abs_path.display(),
+                            repo_root.display()
+                        ),
+                    )
+                })?,
+        };
+        Source::new(&repo_path, abs_path, lines, branches, include_source)
+    }
+
+    /// Creates a source description from lcov style records.
+    /// da_lines - `DA` records as (line number, hits), repeated lines are summed
+    /// saturating at `usize::MAX`
+    /// brda - `BRDA` records, if empty no branch data is included
+    pub fn from_lcov_record(
+        repo_path: &Path,
+        path: &Path,
+        da_lines: &[(usize, usize)],
+        brda: &[BranchData],
+        include_source: bool,
+    ) -> Result<Source, io::Error> {
+        let mut lines = HashMap::new();
+        for &(line, hits) in da_lines {
+            let total = lines.entry(line).or_insert(0usize);
+            *total = total.saturating_add(hits);
+        }
+        let branches = if brda.is_empty() {
+            None
+        } else {
+            Some(brda.to_vec())
+        };
+        Source::new(repo_path, path, &lines, &branches, include_source)
+    }
+}
+
+/// Borrowed view of a source used when serializing a report, allowing report
+/// level settings to change how sources are uploaded
+#[derive(Serialize)]
+struct SourceView<'a> {
+    name: &'a str,
+    #[serde(serialize_with = "hex_digest::serialize")]
+    source_digest: &'a [u8; 16],
+    coverage: &'a [Option<usize>],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branches: Option<&'a Vec<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a String>,
+}
+
+impl<'a> SourceView<'a> {
+    fn new(source: &'a Source, report: &CoverallsReport) -> Self {
+        SourceView {
+            name: &source.name,
+            source_digest: &source.source_digest,
+            coverage: &source.coverage,
+            branches: source.branches.as_ref().filter(|_| report.include_branches),
+            source: source
+                .source
+                .as_ref()
+                .filter(|_| !report.source_too_large(source)),
+            comment: source.comment.as_ref(),
+        }
     }
 }
 
@@ -116,17 +727,184 @@ pub struct Head {
     pub message: String,
 }
 
+impl Head {
+    /// Creates the head commit with the author and committer given as
+    /// (name, email) pairs
+    pub fn from_commit(
+        id: &str,
+        author: (&str, &str),
+        committer: (&str, &str),
+        message: &str,
+    ) -> Self {
+        Head {
+            id: id.to_string(),
+            author_name: author.0.to_string(),
+            author_email: author.1.to_string(),
+            committer_name: committer.0.to_string(),
+            committer_email: committer.1.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Sets the commit message from raw bytes, such as the output of `git log`,
+    /// replacing invalid UTF-8 and sanitizing it like `sanitize_message`
+    pub fn set_message_bytes(&mut self, message: &[u8]) {
+        self.message = String::from_utf8_lossy(message).into_owned();
+        self.sanitize_message();
+    }
+
+    /// Replaces control characters in the commit message other than line
+    /// endings and tabs with the Unicode replacement character
+    pub fn sanitize_message(&mut self) {
+        if self.message.chars().any(is_unsafe_control) {
+            self.message = self
+                .message
+                .chars()
+                .map(|c| if is_unsafe_control(c) { '\u{FFFD}' } else { c })
+                .collect();
+        }
+    }
+
+    /// Truncates the commit message to at most `max_chars` characters
+    pub fn truncate_message(&mut self, max_chars: usize) {
+        if let Some((i, _)) = self.message.char_indices().nth(max_chars) {
+            self.message.truncate(i);
+        }
+    }
+}
+
+/// Control characters removed from commit messages, line endings and tabs
+/// are kept
+fn is_unsafe_control(c: char) -> bool {
+    c.is_control() && !matches!(c, '\n' | '\r' | '\t')
+}
+
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize, Serialize)]
 pub struct Remote {
     pub name: String,
     pub url: String,
 }
 
+/// Hosting provider of a repository remote
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum RepoProvider {
+    GitHub,
+    GitLab,
+    Bitbucket,
+}
+
+impl Remote {
+    /// Identifies the hosting provider from the host of the remote url.
+    /// Handles both urls with a scheme such as `https://github.com/owner/repo`
+    /// and scp-like ssh urls such as `git@github.com:owner/repo.git`. Returns
+    /// `None` for self-hosted or unknown hosts.
+    pub fn provider(&self) -> Option<RepoProvider> {
+        let url = self.url.trim();
+        let rest = match url.find("://") {
+            Some(i) => &url[i + 3..],
+            None => url,
+        };
+        let authority = rest.split('/').next().unwrap_or_default();
+        let host = authority.rsplit('@').next().unwrap_or_default();
+        let host = host
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match host.trim_start_matches("www.") {
+            "github.com" => Some(RepoProvider::GitHub),
+            "gitlab.com" => Some(RepoProvider::GitLab),
+            "bitbucket.org" => Some(RepoProvider::Bitbucket),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize, Serialize)]
 pub struct GitInfo {
     pub head: Head,
     pub branch: String,
     pub remotes: Vec<Remote>,
+    /// Set if the repository is a shallow clone, see `is_shallow`. This is a
+    /// diagnostic for missing base branch coverage and isn't sent to coveralls.
+    #[serde(skip)]
+    pub shallow: bool,
+}
+
+impl GitInfo {
+    /// Checks if the repository at `path` is a shallow clone, e.g. from
+    /// `git clone --depth 1` as done by many CI services. Shallow clones lack
+    /// the history coveralls needs to find the base of a pull request, so
+    /// base branch coverage may be missing. `path` can be the root of the
+    /// work tree or the git directory.
+    pub fn is_shallow(path: &Path) -> bool {
+        let dot_git = path.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            // Work trees and submodules have a file pointing to the git dir
+            match std::fs::read_to_string(&dot_git) {
+                Ok(s) => match s.trim().strip_prefix("gitdir:") {
+                    Some(dir) => path.join(dir.trim()),
+                    None => return false,
+                },
+                Err(_) => return false,
+            }
+        } else {
+            path.to_path_buf()
+        };
+        git_dir.join("shallow").is_file()
+    }
+
+    /// Creates git information from environment variables without needing the
+    /// repository or git binary. Currently supports GitHub Actions where the
+    /// author, committer and message are read from the event payload, with the
+    /// message sanitized and truncated to `DEFAULT_MAX_MESSAGE_CHARS`. Whether
+    /// the checkout in `GITHUB_WORKSPACE` is shallow is recorded in `shallow`.
+    pub fn from_env() -> Option<GitInfo> {
+        let id = var("GITHUB_SHA").ok()?;
+        let branch = match var("GITHUB_HEAD_REF") {
+            Ok(ref s) if !s.is_empty() => s.to_string(),
+            _ => var("GITHUB_REF")
+                .map(|r| r.trim_start_matches("refs/heads/").to_string())
+                .unwrap_or_default(),
+        };
+        let event = github_event();
+        let commit = &event["head_commit"];
+        let field = |v: &serde_json::Value| v.as_str().unwrap_or_default().to_string();
+        let mut git = GitInfo {
+            head: Head {
+                id,
+                author_name: field(&commit["author"]["name"]),
+                author_email: field(&commit["author"]["email"]),
+                committer_name: field(&commit["committer"]["name"]),
+                committer_email: field(&commit["committer"]["email"]),
+                message: field(&commit["message"]),
+            },
+            branch,
+            remotes: vec![],
+            shallow: var("GITHUB_WORKSPACE")
+                .map(|w| GitInfo::is_shallow(Path::new(&w)))
+                .unwrap_or(false),
+        };
+        git.head.sanitize_message();
+        git.head.truncate_message(DEFAULT_MAX_MESSAGE_CHARS);
+        if let (Ok(server), Ok(repo)) = (var("GITHUB_SERVER_URL"), var("GITHUB_REPOSITORY")) {
+            git.add_remote(Remote {
+                name: "origin".to_string(),
+                url: format!("{}/{}.git", server, repo),
+            });
+        }
+        Some(git)
+    }
+
+    /// Adds a remote if an identical one isn't already present. Remotes keep
+    /// the order they were added in.
+    pub fn add_remote(&mut self, remote: Remote) {
+        if !self.remotes.contains(&remote) {
+            self.remotes.push(remote);
+        }
+    }
 }
 
 /// Reports the status of a coveralls report upload.
@@ -143,6 +921,26 @@ pub enum UploadStatus {
     Unknown,
 }
 
+/// Converts the status so `?` can be used on it, only a successful upload is
+/// `Ok`. The server's message isn't known so failures have none.
+impl From<UploadStatus> for Result<(), CoverallsError> {
+    fn from(status: UploadStatus) -> Self {
+        match status {
+            UploadStatus::Succeeded => Ok(()),
+            UploadStatus::Failed(code) => Err(CoverallsError::UploadFailed(code, None)),
+            status => Err(CoverallsError::UploadIncomplete(status)),
+        }
+    }
+}
+
+/// Status of a parallel build sent in the webhook payload
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParallelStatus {
+    /// All jobs in the build have been uploaded
+    Done,
+}
+
 /// Continuous Integration services and the string identifiers coveralls.io
 /// uses to present them.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize, Serialize)]
@@ -153,6 +951,14 @@ pub enum CiService {
     Semaphore,
     Jenkins,
     Codeship,
+    GithubActions,
+    GitLab,
+    Cirrus,
+    Sourcehut,
+    Harness,
+    Concourse,
+    Netlify,
+    Vercel,
     /// Other Ci Service, coveralls-ruby is a valid input which gives same features
     /// as travis for coveralls users.
     Other(String),
@@ -169,6 +975,14 @@ impl FromStr for CiService {
             "semaphore" => CiService::Semaphore,
             "jenkins" => CiService::Jenkins,
             "codeship" => CiService::Codeship,
+            "github" => CiService::GithubActions,
+            "gitlab-ci" => CiService::GitLab,
+            "cirrus-ci" => CiService::Cirrus,
+            "sourcehut" => CiService::Sourcehut,
+            "harness" => CiService::Harness,
+            "concourse-ci" => CiService::Concourse,
+            "netlify" => CiService::Netlify,
+            "vercel" => CiService::Vercel,
             e => CiService::Other(e.to_string()),
         };
         Ok(res)
@@ -188,6 +1002,34 @@ impl CiService {
             Semaphore => "semaphore",
             Jenkins => "jenkins",
             Codeship => "codeship",
+            GithubActions => "github",
+            GitLab => "gitlab-ci",
+            Cirrus => "cirrus-ci",
+            Sourcehut => "sourcehut",
+            Harness => "harness",
+            Concourse => "concourse-ci",
+            Netlify => "netlify",
+            Vercel => "vercel",
+        }
+    }
+
+    /// Whether the environment variables set by this service are present
+    fn detected(&self) -> bool {
+        use CiService::*;
+        match *self {
+            Travis | TravisPro => var("TRAVIS").is_ok(),
+            Circle => var("CIRCLECI").is_ok(),
+            Jenkins => var("JENKINS_URL").is_ok(),
+            Semaphore => var("SEMAPHORE").is_ok(),
+            GithubActions => var("GITHUB_ACTIONS").is_ok(),
+            GitLab => var("GITLAB_CI").is_ok(),
+            Cirrus => var("CIRRUS_CI").is_ok(),
+            Sourcehut => var("JOB_URL").is_ok_and(|u| u.contains("builds.sr.ht")),
+            Harness => var("HARNESS_BUILD_ID").is_ok(),
+            Concourse => var("BUILD_ID").is_ok() && var("BUILD_PIPELINE_NAME").is_ok(),
+            Netlify => var("NETLIFY").is_ok_and(|v| v == "true"),
+            Vercel => var("VERCEL").is_ok_and(|v| v == "1"),
+            Codeship | Other(_) => var("CI_NAME").is_ok_and(|n| n == self.value()),
         }
     }
 }
@@ -199,6 +1041,14 @@ impl CiService {
 /// * Semaphore
 /// * JenkinsCI
 /// * Codeship
+/// * GitHub Actions
+/// * GitLab CI
+/// * Cirrus CI
+/// * Sourcehut builds
+/// * Harness CI
+/// * Concourse CI
+/// * Netlify
+/// * Vercel
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Service {
     /// Name of the CiService
@@ -213,23 +1063,54 @@ pub struct Service {
     pub branch: Option<String>,
     /// Optional service_pull_request
     pub pull_request: Option<String>,
+    /// Optional branch a pull request targets
+    pub base_branch: Option<String>,
+    /// Optional flag_name to distinguish jobs in a parallel build
+    pub flag_name: Option<String>,
+    /// Optional service_attempt for re-runs of the same build
+    pub attempt: Option<String>,
 }
 
 impl Service {
     pub fn from_env() -> Option<Self> {
-        if var("TRAVIS").is_ok() {
-            Some(Self::get_travis_env())
-        } else if var("CIRCLECI").is_ok() {
-            Some(Self::get_circle_env())
-        } else if var("JENKINS_URL").is_ok() {
-            Some(Self::get_jenkins_env())
-        } else if var("SEMAPHORE").is_ok() {
-            Some(Self::get_semaphore_env())
-        } else {
-            Self::get_generic_env()
+        use CiService::*;
+        Self::from_env_ordered(&[
+            Travis,
+            Circle,
+            Jenkins,
+            Semaphore,
+            GithubActions,
+            GitLab,
+            Cirrus,
+            Sourcehut,
+            Harness,
+            Concourse,
+            Netlify,
+            Vercel,
+        ])
+    }
+
+    /// Detects the CI service by checking `providers` in the given order, the
+    /// first one whose environment is present wins. Falls back to the generic
+    /// `CI_*` variables if none of them are detected.
+    pub fn from_env_ordered(providers: &[CiService]) -> Option<Self> {
+        match providers.iter().find(|ci| ci.detected()) {
+            Some(ci) => Self::from_ci(ci.clone()),
+            None => Self::get_generic_env(),
         }
     }
 
+    /// Sets the pull request number, returns an error if `pr` isn't a number
+    /// e.g. a git ref such as `refs/pull/12/merge` which coveralls rejects
+    pub fn set_pull_request(&mut self, pr: &str) -> Result<(), CoverallsError> {
+        let number = pr
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| CoverallsError::InvalidPullRequest(pr.to_string()))?;
+        self.pull_request = Some(number.to_string());
+        Ok(())
+    }
+
     pub fn from_ci(ci: CiService) -> Option<Self> {
         use CiService::*;
         match ci {
@@ -241,6 +1122,14 @@ impl Service {
             Circle => Some(Self::get_circle_env()),
             Semaphore => Some(Self::get_semaphore_env()),
             Jenkins => Some(Self::get_jenkins_env()),
+            GithubActions => Some(Self::get_github_actions_env()),
+            GitLab => Some(Self::get_gitlab_env()),
+            Cirrus => Some(Self::get_cirrus_env()),
+            Sourcehut => Some(Self::get_sourcehut_env()),
+            Harness => Some(Self::get_harness_env()),
+            Concourse => Some(Self::get_concourse_env()),
+            Netlify => Some(Self::get_netlify_env()),
+            Vercel => Some(Self::get_vercel_env()),
             _ => Self::get_generic_env(),
         }
     }
@@ -261,6 +1150,9 @@ impl Service {
             build_url: None,
             pull_request: pr,
             branch: branch,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         }
     }
 
@@ -274,6 +1166,9 @@ impl Service {
             build_url: None,
             pull_request: None,
             branch: branch,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         }
     }
 
@@ -288,6 +1183,9 @@ impl Service {
             build_url: url,
             pull_request: None,
             branch: branch,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         }
     }
 
@@ -301,44 +1199,349 @@ impl Service {
             pull_request: pr,
             branch: None,
             build_url: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         }
     }
 
-    pub fn get_generic_env() -> Option<Self> {
-        let name = var("CI_NAME").ok();
-        let num = var("CI_BUILD_NUMBER").ok();
-        let id = var("CI_JOB_ID").ok();
-        let url = var("CI_BUILD_URL").ok();
-        let branch = var("CI_BRANCH").ok();
-        let pr = var("CI_PULL_REQUEST").ok();
-        if name.is_some()
-            || num.is_some()
-            || id.is_some()
-            || url.is_some()
-            || branch.is_some()
-            || pr.is_some()
-        {
-            let name = name.unwrap_or_else(|| "unknown".to_string());
-
-            Some(Service {
-                name: CiService::from_str(&name).unwrap(),
-                job_id: id,
-                number: num,
-                pull_request: pr,
-                branch: branch,
-                build_url: url,
-            })
+    pub fn get_github_actions_env() -> Self {
+        let id = var("GITHUB_RUN_ID").ok();
+        let url = match (var("GITHUB_SERVER_URL"), var("GITHUB_REPOSITORY"), &id) {
+            (Ok(server), Ok(repo), Some(id)) => {
+                Some(format!("{}/{}/actions/runs/{}", server, repo, id))
+            }
+            _ => None,
+        };
+        // The event payload has the PR number for all pull request events,
+        // GITHUB_REF is only the PR's merge ref for some of them
+        let event_pr = github_event()["pull_request"]["number"]
+            .as_u64()
+            .map(|n| n.to_string());
+        let pr = event_pr.or_else(|| {
+            var("GITHUB_REF").ok().and_then(|r| {
+                r.strip_prefix("refs/pull/")
+                    .and_then(|r| r.split('/').next())
+                    .map(|n| n.to_string())
+            })
+        });
+        let branch = match var("GITHUB_HEAD_REF") {
+            Ok(ref s) if !s.is_empty() => Some(s.to_string()),
+            _ => var("GITHUB_REF_NAME").ok(),
+        };
+        let base = var("GITHUB_BASE_REF").ok().filter(|s| !s.is_empty());
+        // Jobs in a matrix build share a run ID so the attempt and job name are
+        // used to tell the uploads apart
+        let attempt = var("GITHUB_RUN_ATTEMPT").ok();
+        let num = id.as_ref().map(|id| match attempt {
+            Some(ref attempt) => format!("{}-{}", id, attempt),
+            None => id.to_string(),
+        });
+        let flag = var("GITHUB_JOB").ok();
+        Service {
+            name: CiService::GithubActions,
+            job_id: id,
+            number: num,
+            build_url: url,
+            pull_request: pr,
+            branch,
+            base_branch: base,
+            flag_name: flag,
+            attempt,
+        }
+    }
+
+    /// `COVERALLS_SERVICE_NUMBER` and `COVERALLS_SERVICE_JOB_ID` take
+    /// precedence over the pipeline and job IDs when set
+    pub fn get_gitlab_env() -> Self {
+        let id = var("COVERALLS_SERVICE_JOB_ID")
+            .or_else(|_| var("CI_JOB_ID"))
+            .ok();
+        let num = var("COVERALLS_SERVICE_NUMBER")
+            .or_else(|_| var("CI_PIPELINE_ID"))
+            .ok();
+        let url = var("CI_JOB_URL").ok();
+        let branch = var("CI_COMMIT_REF_NAME").ok();
+        let pr = var("CI_MERGE_REQUEST_IID").ok();
+        let base = var("CI_MERGE_REQUEST_TARGET_BRANCH_NAME").ok();
+        Service {
+            name: CiService::GitLab,
+            job_id: id,
+            number: num,
+            build_url: url,
+            pull_request: pr,
+            branch,
+            base_branch: base,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    pub fn get_cirrus_env() -> Self {
+        let id = var("CIRRUS_TASK_ID").ok();
+        let num = var("CIRRUS_BUILD_ID").ok();
+        let branch = var("CIRRUS_BRANCH").ok();
+        let pr = var("CIRRUS_PR").ok().filter(|s| !s.is_empty());
+        Service {
+            name: CiService::Cirrus,
+            job_id: id,
+            number: num,
+            build_url: None,
+            pull_request: pr,
+            branch,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    pub fn get_sourcehut_env() -> Self {
+        let id = var("JOB_ID").ok();
+        let url = var("JOB_URL").ok();
+        let branch = var("GIT_REF")
+            .ok()
+            .map(|r| r.trim_start_matches("refs/heads/").to_string());
+        Service {
+            name: CiService::Sourcehut,
+            job_id: id,
+            number: None,
+            build_url: url,
+            pull_request: None,
+            branch,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    /// Gets service variables from the Harness CI environment
+    pub fn get_harness_env() -> Self {
+        let num = var("HARNESS_BUILD_ID").ok();
+        let url = var("DRONE_BUILD_LINK").ok();
+        let branch = var("DRONE_COMMIT_BRANCH").ok();
+        let pr = var("DRONE_PULL_REQUEST").ok().filter(|s| !s.is_empty());
+        Service {
+            name: CiService::Harness,
+            job_id: None,
+            number: num,
+            build_url: url,
+            pull_request: pr,
+            branch,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    /// Gets service variables from the Concourse CI environment. The build
+    /// URL is only set if the pipeline and job are known, as they're left out
+    /// for one-off builds.
+    pub fn get_concourse_env() -> Self {
+        let id = var("BUILD_ID").ok();
+        let num = var("BUILD_NAME").ok();
+        let url = match (
+            var("ATC_EXTERNAL_URL"),
+            var("BUILD_TEAM_NAME"),
+            var("BUILD_PIPELINE_NAME"),
+            var("BUILD_JOB_NAME"),
+            &num,
+        ) {
+            (Ok(atc), Ok(team), Ok(pipeline), Ok(job), Some(num)) => Some(format!(
+                "{}/teams/{}/pipelines/{}/jobs/{}/builds/{}",
+                atc.trim_end_matches('/'),
+                team,
+                pipeline,
+                job,
+                num
+            )),
+            _ => None,
+        };
+        Service {
+            name: CiService::Concourse,
+            job_id: id,
+            number: num,
+            build_url: url,
+            branch: None,
+            pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    /// Gets service variables from the Netlify build environment
+    pub fn get_netlify_env() -> Self {
+        let id = var("BUILD_ID").ok();
+        let url = var("DEPLOY_URL").ok();
+        let branch = var("BRANCH").ok();
+        let pr = match var("PULL_REQUEST") {
+            Ok(ref s) if s == "true" => var("REVIEW_ID").ok(),
+            _ => None,
+        };
+        Service {
+            name: CiService::Netlify,
+            job_id: id,
+            number: None,
+            build_url: url,
+            branch,
+            pull_request: pr,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    /// Gets service variables from the Vercel build environment
+    pub fn get_vercel_env() -> Self {
+        let id = var("VERCEL_DEPLOYMENT_ID").ok();
+        let url = var("VERCEL_URL").ok().map(|u| format!("https://{}", u));
+        let branch = var("VERCEL_GIT_COMMIT_REF").ok();
+        let pr = var("VERCEL_GIT_PULL_REQUEST_ID")
+            .ok()
+            .filter(|s| !s.is_empty());
+        Service {
+            name: CiService::Vercel,
+            job_id: id,
+            number: None,
+            build_url: url,
+            branch,
+            pull_request: pr,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        }
+    }
+
+    /// Gets service variables from the generic `CI_*` environment variables.
+    /// If `CI_NAME` isn't set the service is reported as `coveralls-ruby`,
+    /// which coveralls accepts with the same features as travis, rather than
+    /// an unknown name coveralls would reject.
+    pub fn get_generic_env() -> Option<Self> {
+        let name = var("CI_NAME").ok();
+        let num = var("COVERALLS_SERVICE_NUMBER")
+            .or_else(|_| var("CI_BUILD_NUMBER"))
+            .ok();
+        let id = var("COVERALLS_SERVICE_JOB_ID")
+            .or_else(|_| var("CI_JOB_ID"))
+            .ok();
+        let url = var("CI_BUILD_URL").ok();
+        let branch = var("CI_BRANCH").ok();
+        let pr = var("CI_PULL_REQUEST").ok();
+        if name.is_some()
+            || num.is_some()
+            || id.is_some()
+            || url.is_some()
+            || branch.is_some()
+            || pr.is_some()
+        {
+            let name = name.unwrap_or_else(|| "coveralls-ruby".to_string());
+
+            Some(Service {
+                name: CiService::from_str(&name).unwrap(),
+                job_id: id,
+                number: num,
+                pull_request: pr,
+                branch: branch,
+                build_url: url,
+                base_branch: None,
+                flag_name: None,
+                attempt: None,
+            })
         } else {
             None
         }
     }
 }
 
+/// Secret coveralls repo token. The `Debug` and `Display` output is redacted
+/// so the token isn't leaked when logged, use `expose` to get the value.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct RepoToken(String);
+
+impl RepoToken {
+    pub fn new(token: String) -> Self {
+        RepoToken(token)
+    }
+
+    /// The secret value of the token
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns true if there's no token
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn redacted(&self) -> &'static str {
+        if self.0.is_empty() {
+            ""
+        } else {
+            "****"
+        }
+    }
+}
+
+impl From<String> for RepoToken {
+    fn from(token: String) -> Self {
+        RepoToken(token)
+    }
+}
+
+impl From<&str> for RepoToken {
+    fn from(token: &str) -> Self {
+        RepoToken(token.to_string())
+    }
+}
+
+impl fmt::Debug for RepoToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("RepoToken").field(&self.redacted()).finish()
+    }
+}
+
+impl fmt::Display for RepoToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.redacted())
+    }
+}
+
+impl Serialize for RepoToken {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
 /// Repo tokens are alternatives to Services and involve a secret token on coveralls
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum Identity {
-    RepoToken(String),
-    ServiceToken(String, Service),
+    RepoToken(RepoToken),
+    ServiceToken(RepoToken, Service),
+    /// GitHub Actions OIDC token, used instead of a repo token, and the
+    /// service of the workflow run
+    GithubOidc(String, Service),
+}
+
+/// How a report authenticates with coveralls
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum AuthKind {
+    /// Repo token only
+    Token,
+    /// CI service job without a repo token
+    Service,
+    /// CI service job with a repo token
+    ServiceWithToken,
+    /// GitHub Actions OIDC token
+    Oidc,
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.redacted_debug())
+    }
 }
 
 impl Identity {
@@ -347,7 +1550,7 @@ impl Identity {
     /// the presence of a .coveralls.yml file
     pub fn from_token() -> Option<Self> {
         match var("COVERALLS_REPO_TOKEN") {
-            Ok(token) => Some(Identity::RepoToken(token)),
+            Ok(token) => Some(Identity::RepoToken(token.into())),
             _ => None,
         }
     }
@@ -355,8 +1558,8 @@ impl Identity {
     /// Creates a report identity based on the CI service auto-detect functionality
     pub fn from_env() -> Option<Self> {
         let token = match var("COVERALLS_REPO_TOKEN") {
-            Ok(token) => token,
-            _ => String::new(),
+            Ok(token) => RepoToken::new(token),
+            _ => RepoToken::default(),
         };
         match Service::from_env() {
             Some(s) => Some(Identity::ServiceToken(token, s)),
@@ -376,12 +1579,414 @@ impl Identity {
         }
     }
 
+    /// Debug representation of the identity with any token masked so it can
+    /// be logged safely
+    pub fn redacted_debug(&self) -> String {
+        match *self {
+            Identity::RepoToken(ref t) => format!("RepoToken({:?})", t.redacted()),
+            Identity::ServiceToken(ref t, ref s) => {
+                format!("ServiceToken({:?}, {:?})", t.redacted(), s)
+            }
+            Identity::GithubOidc(_, ref s) => format!("GithubOidc(\"****\", {:?})", s),
+        }
+    }
+
+    /// The authentication method the report will use, an empty repo token on
+    /// a service identity is treated as no token
+    pub fn auth_kind(&self) -> AuthKind {
+        match *self {
+            Identity::RepoToken(_) => AuthKind::Token,
+            Identity::ServiceToken(ref t, _) if t.is_empty() => AuthKind::Service,
+            Identity::ServiceToken(..) => AuthKind::ServiceWithToken,
+            Identity::GithubOidc(..) => AuthKind::Oidc,
+        }
+    }
+
     pub fn best_match_with_token(token: String) -> Self {
         if let Some(Identity::ServiceToken(_, s)) = Self::from_env() {
-            Identity::ServiceToken(token, s)
+            Identity::ServiceToken(token.into(), s)
         } else {
-            Identity::RepoToken(token)
+            Identity::RepoToken(token.into())
+        }
+    }
+}
+
+#[cfg(feature = "upload")]
+impl Identity {
+    /// Requests an OIDC token for the workflow from GitHub Actions with the
+    /// given audience, the request is made with a client configured from
+    /// `options`. The service is read from the GitHub Actions environment.
+    /// Returns `None` if not running in GitHub Actions or the workflow doesn't
+    /// have the `id-token: write` permission.
+    pub fn from_github_oidc(
+        audience: &str,
+        options: &HttpOptions,
+    ) -> Result<Option<Self>, CoverallsError> {
+        let (url, token) = match (
+            var("ACTIONS_ID_TOKEN_REQUEST_URL"),
+            var("ACTIONS_ID_TOKEN_REQUEST_TOKEN"),
+        ) {
+            (Ok(url), Ok(token)) => (url, token),
+            _ => return Ok(None),
+        };
+        let mut url = Url::parse(&url).map_err(|_| CoverallsError::InvalidUrl(url.to_string()))?;
+        url.query_pairs_mut().append_pair("audience", audience);
+        let mut options = options.clone();
+        options
+            .headers
+            .push(("Authorization".to_string(), format!("Bearer {}", token)));
+        let (code, body) = HttpTransport::with_options(&options)?.get(url.as_str())?;
+        if code != 200 {
+            return Err(CoverallsError::OidcTokenFailed(
+                code,
+                response_message(&body),
+            ));
+        }
+        let json = serde_json::from_slice::<serde_json::Value>(&body)?;
+        Ok(json["value"]
+            .as_str()
+            .map(|v| Identity::GithubOidc(v.to_string(), Service::get_github_actions_env())))
+    }
+}
+
+/// Path of the jobs API, which differs between coveralls deployments
+#[cfg(feature = "upload")]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum JobsPath {
+    /// `/api/v1/jobs` as used by coveralls.io
+    #[default]
+    V1,
+    /// `/api/jobs` as used by some older deployments
+    Unversioned,
+}
+
+#[cfg(feature = "upload")]
+impl JobsPath {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobsPath::V1 => "/api/v1/jobs",
+            JobsPath::Unversioned => "/api/jobs",
+        }
+    }
+}
+
+/// Coveralls instance reports are uploaded to, knows the paths of the API
+#[cfg(feature = "upload")]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum CoverallsEndpoint {
+    /// https://coveralls.io
+    #[default]
+    CoverallsIo,
+    /// Coveralls enterprise or another compatible server given its base URL
+    /// e.g. `https://coveralls.example.com` and the path of its jobs API
+    Enterprise(String, JobsPath),
+}
+
+#[cfg(feature = "upload")]
+impl CoverallsEndpoint {
+    /// Gets the endpoint from the `COVERALLS_ENDPOINT` environment variable,
+    /// coveralls.io if it isn't set
+    pub fn from_env() -> Self {
+        match var("COVERALLS_ENDPOINT") {
+            Ok(ref base) if !base.is_empty() => {
+                CoverallsEndpoint::Enterprise(base.to_string(), JobsPath::V1)
+            }
+            _ => CoverallsEndpoint::CoverallsIo,
+        }
+    }
+
+    /// Gets the endpoint a jobs URL belongs to. The query string is ignored,
+    /// and if the path isn't a known jobs path the server's root is used.
+    fn from_jobs_url(jobs_url: &str) -> Self {
+        let url = jobs_url.split(['?', '#']).next().unwrap_or("");
+        let url = url.trim_end_matches('/');
+        for path in [JobsPath::V1, JobsPath::Unversioned].iter().cloned() {
+            if let Some(base) = url.strip_suffix(path.as_str()) {
+                return CoverallsEndpoint::Enterprise(base.to_string(), path);
+            }
+        }
+        let host_start = url.find("://").map(|i| i + 3).unwrap_or(0);
+        let base = match url[host_start..].find('/') {
+            Some(i) => &url[..host_start + i],
+            None => url,
+        };
+        CoverallsEndpoint::Enterprise(base.to_string(), JobsPath::V1)
+    }
+
+    fn base_url(&self) -> &str {
+        match *self {
+            CoverallsEndpoint::CoverallsIo => "https://coveralls.io",
+            CoverallsEndpoint::Enterprise(ref base, _) => base.trim_end_matches('/'),
+        }
+    }
+
+    fn jobs_path(&self) -> JobsPath {
+        match *self {
+            CoverallsEndpoint::CoverallsIo => JobsPath::V1,
+            CoverallsEndpoint::Enterprise(_, path) => path,
+        }
+    }
+
+    /// URL the reports of each job are uploaded to
+    pub fn jobs_url(&self) -> String {
+        format!("{}{}", self.base_url(), self.jobs_path().as_str())
+    }
+
+    /// URL of the webhook used to finish a parallel build
+    pub fn webhook_url(&self) -> String {
+        format!("{}/webhook", self.base_url())
+    }
+}
+
+/// Transport used to send reports to coveralls. Implement this to use a
+/// different HTTP client or to test uploads without network access.
+#[cfg(feature = "upload")]
+pub trait Transport: Send + Sync {
+    /// Posts the report `body` to `url` with the given content type, with
+    /// `compressed` set if the body is gzipped. Returns the HTTP status code
+    /// and the body of the response.
+    fn send(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        content_type: &str,
+        compressed: bool,
+    ) -> Result<(u32, Vec<u8>), CoverallsError>;
+
+    /// Like `send` but also returns how long the server asked to wait before
+    /// retrying with a `Retry-After` header. The default implementation never
+    /// reports a delay.
+    fn send_with_retry_after(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        content_type: &str,
+        compressed: bool,
+    ) -> Result<(u32, Vec<u8>, Option<Duration>), CoverallsError> {
+        let (code, response) = self.send(url, body, content_type, compressed)?;
+        Ok((code, response, None))
+    }
+
+    /// Posts a JSON `body` to `url`, used for the parallel build webhook.
+    /// Returns the HTTP status code and the body of the response. The default
+    /// implementation uses `send` with the JSON content type.
+    fn send_json(&self, url: &str, body: Vec<u8>) -> Result<(u32, Vec<u8>), CoverallsError> {
+        self.send(url, body, "application/json", false)
+    }
+
+    /// Gets `url`, used to poll the status of an uploaded job. Returns the
+    /// HTTP status code and the body of the response. The default
+    /// implementation doesn't support polling and returns an `Unsupported`
+    /// IO error.
+    fn get(&self, _url: &str) -> Result<(u32, Vec<u8>), CoverallsError> {
+        Err(CoverallsError::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the transport can't poll jobs",
+        )))
+    }
+}
+
+/// Compression of the uploaded report
+#[cfg(feature = "upload")]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum Compression {
+    /// Always gzip the report
+    #[default]
+    Gzip,
+    /// Send the report as plain JSON
+    Uncompressed,
+    /// Only gzip reports which are large enough to benefit from it
+    Auto,
+}
+
+/// HTTP version used when uploading reports
+#[cfg(feature = "upload")]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum HttpVersion {
+    /// Negotiate the version with the server
+    #[default]
+    Auto,
+    /// Only use HTTP/1.1
+    Http1,
+    /// Use HTTP/2 without negotiating it first
+    Http2,
+}
+
+/// HTTP method used when uploading reports
+#[cfg(feature = "upload")]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum HttpMethod {
+    #[default]
+    Post,
+    /// Required by some self-hosted coveralls compatible servers
+    Put,
+}
+
+/// Options used to configure the default HTTP transport
+#[cfg(feature = "upload")]
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct HttpOptions {
+    /// HTTP version to use for uploads
+    pub version: HttpVersion,
+    /// HTTP method to use for uploads
+    pub method: HttpMethod,
+    /// Additional headers sent with every request as (name, value) pairs
+    pub headers: Vec<(String, String)>,
+    /// Send the gzipped report as the request body with an `application/json`
+    /// content type and a `Content-Encoding: gzip` header instead of as a
+    /// multipart form
+    pub gzip_content_encoding: bool,
+    /// Unix domain socket all connections are made through, e.g. a local
+    /// agent proxying to coveralls
+    #[cfg(unix)]
+    pub unix_socket: Option<PathBuf>,
+    /// Overrides DNS resolution for hosts as (host, port, IP address)
+    pub resolve: Vec<(String, u16, String)>,
+    /// Maximum size of the response body kept, anything past it is
+    /// discarded. `DEFAULT_MAX_RESPONSE_BYTES` if not set.
+    pub max_response_bytes: Option<usize>,
+}
+
+/// Default transport, sends the report as a multipart form with reqwest unless
+/// gzip is sent as the content encoding
+#[cfg(feature = "upload")]
+#[derive(Clone, Debug)]
+pub struct HttpTransport {
+    client: Client,
+    method: HttpMethod,
+    gzip_content_encoding: bool,
+    max_response_bytes: usize,
+}
+
+#[cfg(feature = "upload")]
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "upload")]
+impl HttpTransport {
+    pub fn new() -> Self {
+        HttpTransport {
+            client: Client::new(),
+            method: HttpMethod::Post,
+            gzip_content_encoding: false,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    /// Creates a transport with a client configured from the given options
+    pub fn with_options(options: &HttpOptions) -> Result<Self, CoverallsError> {
+        let mut builder = Client::builder();
+        builder = match options.version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in &options.headers {
+            let invalid = || CoverallsError::InvalidHeader(name.to_string());
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+            let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+            headers.append(name, value);
         }
+        builder = builder.default_headers(headers);
+        for (host, port, addr) in &options.resolve {
+            let ip = addr
+                .parse::<IpAddr>()
+                .map_err(|_| CoverallsError::InvalidAddress(addr.to_string()))?;
+            builder = builder.resolve(host, SocketAddr::new(ip, *port));
+        }
+        #[cfg(unix)]
+        if let Some(ref path) = options.unix_socket {
+            builder = builder.unix_socket(path.clone());
+        }
+        Ok(HttpTransport {
+            client: builder.build()?,
+            method: options.method,
+            gzip_content_encoding: options.gzip_content_encoding,
+            max_response_bytes: options
+                .max_response_bytes
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+        })
+    }
+
+    /// Reads the body of the response up to the maximum size
+    fn read_body(&self, response: Response) -> Result<Vec<u8>, CoverallsError> {
+        let mut body = vec![];
+        response
+            .take(self.max_response_bytes as u64)
+            .read_to_end(&mut body)?;
+        Ok(body)
+    }
+}
+
+#[cfg(feature = "upload")]
+impl Transport for HttpTransport {
+    fn send(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        content_type: &str,
+        compressed: bool,
+    ) -> Result<(u32, Vec<u8>), CoverallsError> {
+        let (code, response, _) =
+            self.send_with_retry_after(url, body, content_type, compressed)?;
+        Ok((code, response))
+    }
+
+    fn send_with_retry_after(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        content_type: &str,
+        compressed: bool,
+    ) -> Result<(u32, Vec<u8>, Option<Duration>), CoverallsError> {
+        let method = match self.method {
+            HttpMethod::Post => Method::POST,
+            HttpMethod::Put => Method::PUT,
+        };
+        let request = self.client.request(method, url);
+        let request = if self.gzip_content_encoding && compressed {
+            request
+                .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(body)
+        } else {
+            let part = Part::bytes(body).mime_str(content_type)?;
+            request.multipart(Form::new().part("json_file", part.file_name("report")))
+        };
+        let response = request.send()?;
+
+        let code = response.status().as_u16() as u32;
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        let body = self.read_body(response)?;
+        Ok((code, body, retry_after))
+    }
+
+    fn send_json(&self, url: &str, body: Vec<u8>) -> Result<(u32, Vec<u8>), CoverallsError> {
+        let response = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()?;
+
+        let code = response.status().as_u16() as u32;
+        let body = self.read_body(response)?;
+        Ok((code, body))
+    }
+
+    fn get(&self, url: &str) -> Result<(u32, Vec<u8>), CoverallsError> {
+        let response = self.client.get(url).send()?;
+        let code = response.status().as_u16() as u32;
+        let body = self.read_body(response)?;
+        Ok((code, body))
     }
 }
 
@@ -389,16 +1994,54 @@ impl Identity {
 /// for more details: https://coveralls.zendesk.com/hc/en-us/articles/201350799-API-Reference
 pub struct CoverallsReport {
     id: Identity,
+    /// Name of the repository e.g. `owner/repo`, needed by some enterprise
+    /// deployments
+    repo_name: Option<String>,
     /// List of source files which includes coverage information.
     source_files: Vec<Source>,
     /// Git commit SHA
     commit: Option<String>,
     /// Git information
     git: Option<GitInfo>,
-    /// Client for HTTP requests
-    client: Client,
+    /// Commit SHA of the base the changes are compared against for patch coverage
+    base_commit: Option<String>,
+    /// RFC3339 timestamp of when the coverage was collected
+    run_at: Option<String>,
+    /// Unified diff of the changes for patch coverage
+    diff: Option<String>,
+    /// Custom transport used for HTTP requests, if not set a `HttpTransport`
+    /// is created from `http_options`
+    #[cfg(feature = "upload")]
+    transport: Option<Box<dyn Transport>>,
+    /// Options for the default HTTP transport
+    #[cfg(feature = "upload")]
+    http_options: HttpOptions,
+    /// Compression of the uploaded report
+    #[cfg(feature = "upload")]
+    compression: Compression,
+    /// Query parameters added to the URLs requests are sent to
+    #[cfg(feature = "upload")]
+    query_params: Vec<(String, String)>,
+    /// Longest wait between attempts in `send_with_retries`
+    #[cfg(feature = "upload")]
+    max_retry_wait: Duration,
     /// Last upload status code
     last_status: UploadStatus,
+    /// Message from the server if the last upload was rejected
+    last_message: Option<String>,
+    /// URL of the job created by the last successful upload
+    #[cfg(feature = "upload")]
+    last_job_url: Option<String>,
+    /// Content type of the uploaded report
+    content_type: String,
+    /// Additional top level fields not modelled by this crate
+    extra_fields: BTreeMap<String, serde_json::Value>,
+    /// Whether branch coverage of the sources is uploaded
+    include_branches: bool,
+    /// Whether this report is one job in a parallel build
+    parallel: bool,
+    /// Embedded source contents larger than this are left out of the upload
+    max_embedded_source_bytes: Option<usize>,
 }
 
 impl CoverallsReport {
@@ -407,162 +2050,3587 @@ impl CoverallsReport {
     pub fn new(id: Identity) -> CoverallsReport {
         CoverallsReport {
             id: id,
+            repo_name: None,
             source_files: Vec::new(),
             commit: None,
             git: None,
-            client: Client::new(),
+            base_commit: None,
+            run_at: None,
+            diff: None,
+            #[cfg(feature = "upload")]
+            transport: None,
+            #[cfg(feature = "upload")]
+            http_options: HttpOptions::default(),
+            #[cfg(feature = "upload")]
+            compression: Compression::default(),
+            #[cfg(feature = "upload")]
+            query_params: Vec::new(),
+            #[cfg(feature = "upload")]
+            max_retry_wait: DEFAULT_MAX_RETRY_WAIT,
             last_status: UploadStatus::Pending,
+            last_message: None,
+            #[cfg(feature = "upload")]
+            last_job_url: None,
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            extra_fields: BTreeMap::new(),
+            include_branches: true,
+            parallel: false,
+            max_embedded_source_bytes: None,
         }
     }
 
+    /// Creates a report using the identity and commit detected from the
+    /// environment. Returns `None` if no identity can be found.
+    pub fn from_env() -> Option<CoverallsReport> {
+        let id = Identity::best_match()?;
+        let mut report = CoverallsReport::new(id);
+        report.set_commit_from_env();
+        Some(report)
+    }
+
+    /// Identity used to authenticate the report with coveralls
+    pub fn identity(&self) -> &Identity {
+        &self.id
+    }
+
     /// Add generated source data to coveralls report.
     pub fn add_source(&mut self, source: Source) {
         self.source_files.push(source);
     }
 
-    /// Sets the commit ID. Overrides more detailed git info
-    pub fn set_commit(&mut self, commit: &str) {
-        self.commit = Some(commit.to_string());
-        self.git = None;
+    /// Replaces the source with the same name, returning the old one. The
+    /// source is added if there isn't one with the same name.
+    pub fn replace_source(&mut self, source: Source) -> Option<Source> {
+        match self.source_files.iter_mut().find(|x| x.name == source.name) {
+            Some(old) => Some(std::mem::replace(old, source)),
+            None => {
+                self.source_files.push(source);
+                None
+            }
+        }
     }
 
-    /// Set detailed git information, overrides commit ID if set.
-    pub fn set_detailed_git_info(&mut self, git: GitInfo) {
-        self.git = Some(git);
-        self.commit = None;
+    /// Removes sources whose digest matches the one stored for their name in
+    /// `known`, a map of source names to hex MD5 digests. Used to only upload
+    /// files which have changed.
+    pub fn filter_unchanged(&mut self, known: &HashMap<String, String>) {
+        self.source_files
+            .retain(|x| known.get(&x.name) != Some(&x.digest()));
     }
 
-    /// Send report to the coveralls.io directly. For coveralls hosted on other
-    /// platforms see send_to_endpoint
-    pub fn send_to_coveralls(&mut self) -> Result<(), reqwest::Error> {
-        self.send_to_endpoint("https://coveralls.io/api/v1/jobs")
-    }
+    /// Creates sources for many files at once, reading and hashing the files
+    /// on a thread pool. The sources are added in the order given. If any file
+    /// can't be read none are added.
+    #[cfg(feature = "rayon")]
+    pub fn add_sources_parallel(
+        &mut self,
+        files: Vec<SourceFile>,
+        include_source: bool,
+    ) -> Result<(), io::Error> {
+        use rayon::prelude::*;
 
-    /// Sends coveralls report to the specified url
-    pub fn send_to_endpoint(&mut self, url: &str) -> Result<(), reqwest::Error> {
-        let body = match serde_json::to_vec(&self) {
-            Ok(body) => body,
-            Err(e) => panic!("Error {}", e),
-        };
+        let sources = files
+            .par_iter()
+            .map(|(repo_path, path, lines, branches)| {
+                Source::new(repo_path, path, lines, branches, include_source)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.source_files.extend(sources);
+        Ok(())
+    }
 
-        let body = deflate_bytes_gzip(&body);
+    /// Keeps only the sources for which `f` returns true
+    pub fn retain_sources<F: Fn(&Source) -> bool>(&mut self, f: F) {
+        self.source_files.retain(|x| f(x));
+    }
 
-        let form = Form::new().part(
-            "json_file",
-            Part::bytes(body).mime_str("gzip/json")?.file_name("report"),
-        );
+    /// Keeps only the sources whose name matches the glob `pattern`
+    #[cfg(feature = "glob")]
+    pub fn retain_by_glob(&mut self, pattern: &str) -> Result<(), glob::PatternError> {
+        let pattern = glob::Pattern::new(pattern)?;
+        self.retain_sources(|x| pattern.matches(&x.name));
+        Ok(())
+    }
 
-        let response = self.client.post(url).multipart(form).send()?;
+    /// Removes the sources whose name matches any of the glob patterns in an
+    /// ignore file such as `.coverallsignore`. The file has one pattern per
+    /// line, blank lines and lines starting with `#` are skipped.
+    #[cfg(feature = "glob")]
+    pub fn apply_ignore_file(&mut self, path: &Path) -> Result<(), CoverallsError> {
+        let content = std::fs::read_to_string(path)?;
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(glob::Pattern::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.retain_sources(|x| !patterns.iter().any(|p| p.matches(&x.name)));
+        Ok(())
+    }
+
+    /// Removes the embedded contents of every source, e.g. before uploading a
+    /// report built with `include_source` to public coveralls. The digests
+    /// are kept.
+    pub fn strip_sources(&mut self) {
+        for source in &mut self.source_files {
+            source.source = None;
+        }
+    }
+
+    /// Returns true if any source in the report has branch data
+    pub fn has_branch_coverage(&self) -> bool {
+        self.source_files
+            .iter()
+            .any(|x| x.branches.as_ref().is_some_and(|b| !b.is_empty()))
+    }
+
+    /// Sorts the sources by name so the serialized report doesn't depend on
+    /// the order sources were added in
+    pub fn sort_sources(&mut self) {
+        self.source_files.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Sets the commit ID. Overrides more detailed git info
+    pub fn set_commit(&mut self, commit: &str) {
+        self.commit = Some(commit.to_string());
+        self.git = None;
+    }
+
+    /// Sets the commit ID from the environment variables set by supported CI
+    /// services. Returns true if a commit was found.
+    pub fn set_commit_from_env(&mut self) -> bool {
+        let vars = [
+            "GITHUB_SHA",
+            "CI_COMMIT_SHA",
+            "TRAVIS_COMMIT",
+            "CIRCLE_SHA1",
+            "SEMAPHORE_GIT_SHA",
+            "GIT_COMMIT",
+            "VERCEL_GIT_COMMIT_SHA",
+            "COMMIT_REF",
+        ];
+        match vars.iter().filter_map(|v| var(v).ok()).next() {
+            Some(commit) => {
+                self.set_commit(&commit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set detailed git information, overrides commit ID if set.
+    pub fn set_detailed_git_info(&mut self, git: GitInfo) {
+        self.git = Some(git);
+        self.commit = None;
+    }
+
+    /// Sets both the commit ID and detailed git information, for servers which
+    /// expect `commit_sha` alongside `git`
+    pub fn set_commit_and_git(&mut self, sha: &str, git: GitInfo) {
+        self.commit = Some(sha.to_string());
+        self.git = Some(git);
+    }
+
+    /// Sets the branch in both the detailed git information and the CI service
+    /// so they're consistent, e.g. for builds of a detached HEAD. Only the
+    /// ones which are present in the report are updated.
+    pub fn set_branch(&mut self, branch: &str) {
+        if let Some(ref mut git) = self.git {
+            git.branch = branch.to_string();
+        }
+        if let Identity::ServiceToken(_, ref mut serv) | Identity::GithubOidc(_, ref mut serv) =
+            self.id
+        {
+            serv.branch = Some(branch.to_string());
+        }
+    }
+
+    /// The commit ID that will be uploaded, if set with `set_commit`
+    pub fn commit(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+
+    /// The detailed git information that will be uploaded, if set
+    pub fn git(&self) -> Option<&GitInfo> {
+        self.git.as_ref()
+    }
+
+    /// Sets the name of the repository e.g. `owner/repo`, required by some
+    /// coveralls enterprise deployments instead of inferring it from the token
+    pub fn set_repo_name(&mut self, name: &str) {
+        self.repo_name = Some(name.to_string());
+    }
+
+    /// Sets the commit SHA of the base the changes are compared to, letting
+    /// coveralls compute the coverage of the changed lines
+    pub fn set_base_commit(&mut self, sha: &str) {
+        self.base_commit = Some(sha.to_string());
+    }
+
+    /// Attaches a unified diff of the changes, e.g. from `git diff`, sent as
+    /// the `diff` field. Deployments which support it compute the patch
+    /// coverage from the changed lines in the diff.
+    pub fn set_diff(&mut self, unified_diff: String) {
+        self.diff = Some(unified_diff);
+    }
+
+    /// Sets when the coverage was collected, `timestamp` should be in RFC3339
+    /// format e.g. `2019-02-12T08:48:27+00:00`
+    pub fn set_run_at(&mut self, timestamp: &str) {
+        self.run_at = Some(timestamp.to_string());
+    }
+
+    /// Sets a top level field in the report which isn't otherwise supported by
+    /// this crate. Fields are serialized after all the known fields.
+    pub fn set_extra_field(&mut self, key: &str, value: serde_json::Value) {
+        self.extra_fields.insert(key.to_string(), value);
+    }
+
+    /// Sets the service number used to group jobs into a build. If the report
+    /// identity is a repo token a generic service is added to hold the number.
+    /// OIDC identities have no service so it's ignored for them.
+    pub fn set_service_number(&mut self, number: &str) {
+        if let Some(serv) = self.service_mut() {
+            serv.number = Some(number.to_string());
+        }
+    }
+
+    /// Overrides the name of the CI service sent to coveralls, e.g. to report
+    /// jobs as `coveralls-ruby` for the same features as travis. If the report
+    /// identity is a repo token a service is added to hold the name.
+    pub fn set_service_name(&mut self, name: CiService) {
+        if let Some(serv) = self.service_mut() {
+            serv.name = name;
+        }
+    }
+
+    /// Gets the CI service of the report, adding a generic one if the identity
+    /// is a repo token
+    fn service_mut(&mut self) -> Option<&mut Service> {
+        if let Identity::RepoToken(ref token) = self.id {
+            let serv = Service {
+                name: CiService::Other("coveralls-ruby".to_string()),
+                job_id: None,
+                number: None,
+                build_url: None,
+                branch: None,
+                pull_request: None,
+                base_branch: None,
+                flag_name: None,
+                attempt: None,
+            };
+            self.id = Identity::ServiceToken(token.clone(), serv);
+        }
+        match self.id {
+            Identity::ServiceToken(_, ref mut serv) | Identity::GithubOidc(_, ref mut serv) => {
+                Some(serv)
+            }
+            _ => None,
+        }
+    }
+
+    /// Marks the report as one job in a parallel build. Coveralls waits for
+    /// the done webhook (see `send_done`) before merging the jobs.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    /// Sets whether branch coverage is uploaded for the sources, by default it
+    /// is included whenever a source has branch data
+    pub fn set_include_branches(&mut self, include: bool) {
+        self.include_branches = include;
+    }
+
+    /// Sets the maximum size of embedded source contents, larger sources are
+    /// uploaded without their contents. Use `omitted_sources` to find which
+    /// sources this applies to.
+    pub fn set_max_embedded_source_bytes(&mut self, max: usize) {
+        self.max_embedded_source_bytes = Some(max);
+    }
+
+    /// Names of the sources whose embedded contents are left out of the upload
+    /// because they exceed the maximum embedded source size
+    pub fn omitted_sources(&self) -> Vec<&str> {
+        self.source_files
+            .iter()
+            .filter(|x| self.source_too_large(x))
+            .map(|x| x.name.as_str())
+            .collect()
+    }
+
+    /// Whether the embedded contents of `source` exceed the maximum size
+    fn source_too_large(&self, source: &Source) -> bool {
+        match (self.max_embedded_source_bytes, &source.source) {
+            (Some(max), Some(content)) => content.len() > max,
+            _ => false,
+        }
+    }
+
+    /// Human readable summary of the report to print before uploading, with
+    /// the number of sources, line coverage, CI service, commit and whether
+    /// branch data and source contents are uploaded
+    pub fn summary(&self) -> String {
+        let relevant = self
+            .source_files
+            .iter()
+            .flat_map(|x| x.coverage.iter().flatten())
+            .count();
+        let covered = self
+            .source_files
+            .iter()
+            .map(|x| x.covered_lines().count())
+            .sum::<usize>();
+        let coverage = if relevant == 0 {
+            0.0
+        } else {
+            100.0 * covered as f64 / relevant as f64
+        };
+        let ci = match self.id {
+            Identity::ServiceToken(_, ref s) | Identity::GithubOidc(_, ref s) => s.name.value(),
+            _ => "none",
+        };
+        let commit = self
+            .commit
+            .as_deref()
+            .or_else(|| self.git.as_ref().map(|g| g.head.id.as_str()))
+            .map(|c| c.get(..7).unwrap_or(c))
+            .unwrap_or("none");
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        let branches = self.include_branches && self.has_branch_coverage();
+        let embedded = self
+            .source_files
+            .iter()
+            .any(|x| x.source.is_some() && !self.source_too_large(x));
+        format!(
+            "Sources: {}\nLine coverage: {:.2}% ({}/{})\nCI: {}\nCommit: {}\nBranch data: {}\nEmbedded source: {}",
+            self.source_files.len(),
+            coverage,
+            covered,
+            relevant,
+            ci,
+            commit,
+            yes_no(branches),
+            yes_no(embedded)
+        )
+    }
+
+    /// The uncompressed JSON report as it's sent to coveralls
+    pub fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    /// Writes the uncompressed JSON report to `path`, this can be uploaded
+    /// later e.g. with the coveralls CLI's `--input` option. Use
+    /// `save_to_file` for the exact upload request body instead
+    pub fn write_to_file(&self, path: &Path) -> Result<(), CoverallsError> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Sets the content type of the uploaded report, some coveralls compatible
+    /// servers reject the default and expect `application/gzip`
+    pub fn set_content_type(&mut self, ct: &str) {
+        self.content_type = ct.to_string();
+    }
+
+    pub fn upload_status(&mut self) -> UploadStatus {
+        self.last_status
+    }
+
+    /// Gets the status of the last upload, if it failed an error is returned
+    /// with the reason given by the server for client errors such as 422
+    pub fn upload_result(&self) -> Result<UploadStatus, CoverallsError> {
+        match self.last_status {
+            UploadStatus::Failed(code) => Err(CoverallsError::UploadFailed(
+                code,
+                self.last_message.clone(),
+            )),
+            status => Ok(status),
+        }
+    }
+}
+
+#[cfg(feature = "upload")]
+impl CoverallsReport {
+    /// Sets the transport used to send the report, replacing the default
+    /// reqwest based transport. Any `HttpOptions` are ignored by custom
+    /// transports.
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) {
+        self.transport = Some(transport);
+    }
+
+    /// Adds a query parameter to the URLs reports and webhooks are sent to,
+    /// for coveralls compatible servers which expect e.g. `?token=`. The key
+    /// and value are URL encoded.
+    pub fn add_query_param(&mut self, key: &str, value: &str) {
+        self.query_params.push((key.to_string(), value.to_string()));
+    }
+
+    /// Adds a header sent with every request by the default transport, for
+    /// example authorization for a reverse proxy in front of coveralls
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.http_options
+            .headers
+            .push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets the HTTP method used by the default transport, POST by default
+    pub fn set_http_method(&mut self, method: HttpMethod) {
+        self.http_options.method = method;
+    }
+
+    /// Sets the HTTP version used by the default transport, by default the
+    /// version is negotiated with the server
+    pub fn set_http_version(&mut self, version: HttpVersion) {
+        self.http_options.version = version;
+    }
+
+    /// Makes the default transport send the gzipped report as the request
+    /// body with an `application/json` content type and a
+    /// `Content-Encoding: gzip` header rather than as a multipart form, for
+    /// servers and proxies which don't understand the `gzip/json` content
+    /// type. This takes precedence over `set_content_type`.
+    pub fn set_gzip_as_content_encoding(&mut self, enabled: bool) {
+        self.http_options.gzip_content_encoding = enabled;
+    }
+
+    /// Sets whether the uploaded report is compressed, gzipped by default.
+    /// Uncompressed reports are sent as `application/json`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Makes the default transport connect to `addr` for requests to `host`,
+    /// skipping DNS. Useful for internal mirrors and air-gapped environments.
+    /// As DNS has no notion of ports the port must also be in the endpoint URL.
+    pub fn add_resolve(&mut self, host: &str, port: u16, addr: &str) {
+        self.http_options
+            .resolve
+            .push((host.to_string(), port, addr.to_string()));
+    }
+
+    /// Sets the longest `send_with_retries` waits between attempts, so a
+    /// server asking for a huge `Retry-After` can't hang the upload.
+    /// `DEFAULT_MAX_RETRY_WAIT` by default.
+    pub fn set_max_retry_wait(&mut self, max: Duration) {
+        self.max_retry_wait = max;
+    }
+
+    /// Sets the maximum size of the response body kept by the default
+    /// transport, larger responses are truncated. Protects against broken
+    /// servers sending huge responses, `DEFAULT_MAX_RESPONSE_BYTES` by default.
+    pub fn set_max_response_bytes(&mut self, max: usize) {
+        self.http_options.max_response_bytes = Some(max);
+    }
+
+    /// Makes the default transport connect through a Unix domain socket
+    /// instead of TCP. The host in the endpoint URL is still sent in the
+    /// request, and TLS is used over the socket for `https` URLs.
+    #[cfg(unix)]
+    pub fn set_unix_socket(&mut self, path: &Path) {
+        self.http_options.unix_socket = Some(path.to_path_buf());
+    }
+
+    /// Checks the HTTP client was built with TLS so reports can be uploaded
+    /// over HTTPS. Without one of the `rustls-tls` or `native-tls` features
+    /// uploads to coveralls fail with an unhelpful connection error.
+    pub fn check_tls_support() -> Result<(), CoverallsError> {
+        if cfg!(any(feature = "rustls-tls", feature = "native-tls")) {
+            Ok(())
+        } else {
+            Err(CoverallsError::TlsUnsupported)
+        }
+    }
+
+    /// Sends the report to the coveralls instance in the `COVERALLS_ENDPOINT`
+    /// environment variable e.g. `https://coveralls.example.com`, or
+    /// coveralls.io if it isn't set
+    pub fn send(&mut self) -> Result<Option<String>, CoverallsError> {
+        let url = CoverallsEndpoint::from_env().jobs_url();
+        self.send_to_endpoint(&url)
+    }
+
+    /// Send report to the coveralls.io directly. For coveralls hosted on other
+    /// platforms see send_to_endpoint. Returns the URL of the coverage job if
+    /// the upload succeeded and coveralls returned one.
+    pub fn send_to_coveralls(&mut self) -> Result<Option<String>, CoverallsError> {
+        self.send_to_endpoint(&CoverallsEndpoint::CoverallsIo.jobs_url())
+    }
+
+    /// Sends coveralls report to the specified url. Returns the URL of the
+    /// coverage job if the upload succeeded and the server returned one.
+    pub fn send_to_endpoint(&mut self, url: &str) -> Result<Option<String>, CoverallsError> {
+        let (_, response) = self.send_to_endpoint_raw(url)?;
+        Ok(job_url(self.last_status, &response))
+    }
+
+    /// Sends coveralls report to the specified url returning the HTTP status
+    /// code and the unparsed body of the response
+    pub fn send_to_endpoint_raw(&mut self, url: &str) -> Result<(u32, Vec<u8>), CoverallsError> {
+        let (code, response, _) = self.upload(url)?;
+        Ok((code, response))
+    }
+
+    /// Sends the report to `url`, retrying up to `max_retries` times if the
+    /// server is rate limiting (429) or has an error (5xx). Waits as long as
+    /// the `Retry-After` header of the response asks up to the maximum set
+    /// with `set_max_retry_wait`, or a second if it's missing. Returns the URL
+    /// of the coverage job like `send_to_endpoint`.
+    pub fn send_with_retries(
+        &mut self,
+        url: &str,
+        max_retries: usize,
+    ) -> Result<Option<String>, CoverallsError> {
+        let mut attempt = 0;
+        loop {
+            let (code, response, retry_after) = self.upload(url)?;
+            let retryable = code == 429 || (500..=599).contains(&code);
+            if !retryable || attempt >= max_retries {
+                return Ok(job_url(self.last_status, &response));
+            }
+            attempt += 1;
+            let wait = retry_after.unwrap_or_else(|| Duration::from_secs(1));
+            std::thread::sleep(wait.min(self.max_retry_wait));
+        }
+    }
+
+    /// Sends the report to `url` and records the upload status
+    fn upload(&mut self, url: &str) -> Result<(u32, Vec<u8>, Option<Duration>), CoverallsError> {
+        let (body, content_type, compressed) = self.payload()?;
+        let url = self.endpoint_url(url)?;
+        let (code, response, retry_after) =
+            self.with_transport(|t| t.send_with_retry_after(&url, body, content_type, compressed))?;
 
-        let code = response.status();
         self.last_status = match code {
-            StatusCode::OK => UploadStatus::Succeeded,
-            _ => UploadStatus::Failed(code.as_u16() as u32),
+            200 => UploadStatus::Succeeded,
+            _ => UploadStatus::Failed(code),
+        };
+        self.last_message = match code {
+            400..=499 => response_message(&response),
+            _ => None,
+        };
+        self.last_job_url = job_url(self.last_status, &response);
+
+        Ok((code, response, retry_after))
+    }
+
+    /// Waits for coveralls to finish processing the job created by the last
+    /// upload, polling the job's JSON every `poll_interval` through the
+    /// transport. The job is pending until it reports its coverage. Returns
+    /// `Pending` if it isn't done within `timeout`, and the upload status
+    /// straight away if the upload failed, didn't return a job URL or the
+    /// transport can't poll. Failed polls, e.g. a 401 for the job of a private
+    /// repo, leave the job pending and are retried until the timeout.
+    pub fn wait_for_completion(
+        &mut self,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> UploadStatus {
+        let url = match self.last_job_url {
+            Some(ref url) => format!("{}.json", url.trim_end_matches('/')),
+            None => return self.last_status,
+        };
+        let start = Instant::now();
+        loop {
+            let status = match self.with_transport(|t| t.get(&url)) {
+                Ok((code, body)) => job_status(code, &body),
+                Err(CoverallsError::Io(ref e)) if e.kind() == io::ErrorKind::Unsupported => {
+                    return self.last_status;
+                }
+                Err(_) => UploadStatus::Pending,
+            };
+            if status != UploadStatus::Pending || start.elapsed() >= timeout {
+                return status;
+            }
+            std::thread::sleep(poll_interval.min(timeout.saturating_sub(start.elapsed())));
+        }
+    }
+
+    /// Sends the sources as several jobs of a parallel build with at most
+    /// `max_sources_per_chunk` sources each, then sends the done webhook.
+    /// Used to work around body size limits for very large reports. The jobs
+    /// are grouped by the service number so one is required. Each chunk is sent
+    /// with its own job ID, `<job ID>-<n>`, so coveralls doesn't treat the
+    /// chunks as one job resubmitted.
+    pub fn send_chunked(
+        &mut self,
+        url: &str,
+        max_sources_per_chunk: usize,
+    ) -> Result<UploadStatus, CoverallsError> {
+        if self.service_number().is_none() {
+            return Err(CoverallsError::MissingServiceNumber);
+        }
+        let sources = std::mem::take(&mut self.source_files);
+        let parallel = self.parallel;
+        self.parallel = true;
+        let job_id = match self.id {
+            Identity::ServiceToken(_, ref s) | Identity::GithubOidc(_, ref s) => s.job_id.clone(),
+            _ => None,
+        };
+        let base_id = job_id
+            .clone()
+            .or_else(|| self.service_number().map(String::from))
+            .unwrap_or_default();
+
+        let mut result = Ok(());
+        for (n, chunk) in sources.chunks(max_sources_per_chunk.max(1)).enumerate() {
+            if let Some(serv) = self.service_mut() {
+                serv.job_id = Some(format!("{}-{}", base_id, n + 1));
+            }
+            self.source_files = chunk.to_vec();
+            result = self.send_to_endpoint_raw(url).map(|_| ());
+            if result.is_err() || self.last_status != UploadStatus::Succeeded {
+                break;
+            }
+        }
+
+        if let Some(serv) = self.service_mut() {
+            serv.job_id = job_id;
+        }
+        self.source_files = sources;
+        self.parallel = parallel;
+        result?;
+        if self.last_status != UploadStatus::Succeeded {
+            return Ok(self.last_status);
+        }
+        self.send_done(&CoverallsEndpoint::from_jobs_url(url).webhook_url())
+    }
+
+    /// Tells the given coveralls instance all jobs of a parallel build have
+    /// been uploaded, see `send_done`
+    pub fn finish_parallel(
+        &self,
+        endpoint: &CoverallsEndpoint,
+    ) -> Result<UploadStatus, CoverallsError> {
+        self.send_done(&endpoint.webhook_url())
+    }
+
+    /// Sends the webhook telling coveralls all jobs of a parallel build have
+    /// been uploaded. The build is identified by the service number.
+    pub fn send_done(&self, url: &str) -> Result<UploadStatus, CoverallsError> {
+        let build_num = self
+            .service_number()
+            .ok_or(CoverallsError::MissingServiceNumber)?;
+        let mut body = serde_json::json!({
+            "payload": {
+                "build_num": build_num,
+                "status": ParallelStatus::Done,
+            }
+        });
+        match self.id {
+            Identity::RepoToken(ref r) | Identity::ServiceToken(ref r, _) if !r.is_empty() => {
+                body["repo_token"] = r.expose().into();
+            }
+            Identity::GithubOidc(ref t, _) => body["oidc_token"] = t.as_str().into(),
+            _ => {}
+        }
+        let body = serde_json::to_vec(&body)?;
+
+        let url = self.endpoint_url(url)?;
+        let (code, _) = self.with_transport(|t| t.send_json(&url, body))?;
+        let status = match code {
+            200 => UploadStatus::Succeeded,
+            _ => UploadStatus::Failed(code),
+        };
+        Ok(status)
+    }
+
+    /// Service number of the build if one is known
+    fn service_number(&self) -> Option<&str> {
+        match self.id {
+            Identity::ServiceToken(_, ref s) | Identity::GithubOidc(_, ref s) => {
+                s.number.as_deref()
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks the report survives serialization and compression by
+    /// decompressing the payload that would be uploaded and comparing it to
+    /// the report. Useful as a safety net before a network round trip.
+    pub fn self_check(&self) -> Result<(), CoverallsError> {
+        let (payload, _, compressed) = self.payload()?;
+        let json = if compressed {
+            let mut json = vec![];
+            GzDecoder::new(payload.as_slice()).read_to_end(&mut json)?;
+            json
+        } else {
+            payload
+        };
+        let decoded: serde_json::Value = serde_json::from_slice(&json)?;
+        if decoded == serde_json::to_value(self)? {
+            Ok(())
+        } else {
+            Err(CoverallsError::CorruptPayload)
+        }
+    }
+
+    /// Size in bytes of the report that would be uploaded, after compression
+    /// if enabled. Useful to warn about huge reports e.g. from accidentally
+    /// embedded sources.
+    pub fn estimated_payload_size(&self) -> Result<usize, CoverallsError> {
+        Ok(self.payload()?.0.len())
+    }
+
+    /// Writes the report to `path` exactly as it would be uploaded, gzipped
+    /// unless set otherwise with `set_compression`. This can be posted to
+    /// coveralls later, e.g. with curl from an air-gapped machine. Unlike
+    /// `write_to_file` the output is the request body, not plain JSON.
+    pub fn save_to_file(&self, path: &Path) -> Result<(), CoverallsError> {
+        let (body, _, _) = self.payload()?;
+        std::fs::write(path, body)?;
+        Ok(())
+    }
+
+    /// Serializes and compresses the report as it's sent to coveralls,
+    /// returning the body, its content type and whether it's compressed
+    fn payload(&self) -> Result<(Vec<u8>, &str, bool), CoverallsError> {
+        let json = self.to_json()?;
+        let compress = match self.compression {
+            Compression::Gzip => true,
+            Compression::Uncompressed => false,
+            Compression::Auto => json.len() >= AUTO_COMPRESSION_THRESHOLD,
+        };
+        if compress {
+            Ok((gzip(&json)?, self.content_type.as_str(), true))
+        } else {
+            Ok((json, JSON_CONTENT_TYPE, false))
+        }
+    }
+
+    /// Adds the query parameters to `url`
+    fn endpoint_url(&self, url: &str) -> Result<String, CoverallsError> {
+        if self.query_params.is_empty() {
+            return Ok(url.to_string());
+        }
+        let mut url = Url::parse(url).map_err(|_| CoverallsError::InvalidUrl(url.to_string()))?;
+        url.query_pairs_mut().extend_pairs(&self.query_params);
+        Ok(url.into())
+    }
+
+    /// Sends the report to `url` and gives the resulting upload status
+    fn upload_status_for(&mut self, url: &str) -> Result<UploadStatus, CoverallsError> {
+        self.upload(url)?;
+        Ok(self.last_status)
+    }
+
+    /// Runs `f` with the custom transport if one is set, otherwise with the
+    /// default HTTP transport
+    fn with_transport<T, F>(&self, f: F) -> Result<T, CoverallsError>
+    where
+        F: FnOnce(&dyn Transport) -> Result<T, CoverallsError>,
+    {
+        match self.transport {
+            Some(ref t) => f(t.as_ref()),
+            None => f(&HttpTransport::with_options(&self.http_options)?),
+        }
+    }
+}
+
+/// Compresses `data` with gzip using the deflate crate
+#[cfg(all(feature = "upload", not(feature = "flate2")))]
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    Ok(deflate_bytes_gzip(data))
+}
+
+/// Compresses `data` with gzip using flate2, preferred over deflate if both
+/// features are enabled
+#[cfg(feature = "flate2")]
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Uploads the jobs of a parallel build to the jobs `url` concurrently, then
+/// sends the done webhook once all of them have succeeded. Every report is
+/// marked as parallel and given `build_num` as its service number. Returns
+/// the status of the first failed upload, otherwise the webhook's status.
+#[cfg(feature = "upload")]
+pub fn run_parallel_upload(
+    mut reports: Vec<CoverallsReport>,
+    build_num: &str,
+    url: &str,
+) -> Result<UploadStatus, CoverallsError> {
+    for report in &mut reports {
+        report.set_service_number(build_num);
+        report.set_parallel(true);
+    }
+    validate_parallel(&reports.iter().collect::<Vec<_>>())?;
+
+    let results = std::thread::scope(|scope| {
+        let handles = reports
+            .iter_mut()
+            .map(|report| scope.spawn(move || report.send_to_endpoint_raw(url)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("upload thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    for result in results {
+        result?;
+    }
+    if let Some(failed) = reports
+        .iter()
+        .find(|r| r.last_status != UploadStatus::Succeeded)
+    {
+        return Ok(failed.last_status);
+    }
+    match reports.first() {
+        Some(report) => report.send_done(&CoverallsEndpoint::from_jobs_url(url).webhook_url()),
+        None => Ok(UploadStatus::Pending),
+    }
+}
+
+/// Reports for several commits submitted together, e.g. when backfilling
+/// coverage for a range of commits. Reports are keyed by commit SHA.
+#[cfg(feature = "upload")]
+#[derive(Default)]
+pub struct ReportBatch {
+    reports: BTreeMap<String, CoverallsReport>,
+}
+
+#[cfg(feature = "upload")]
+impl ReportBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the report for `commit`, returning the report previously added
+    /// for it. The commit ID of the report is set to `commit` if it has no
+    /// commit or git information.
+    pub fn add_report(
+        &mut self,
+        commit: &str,
+        mut report: CoverallsReport,
+    ) -> Option<CoverallsReport> {
+        if report.commit.is_none() && report.git.is_none() {
+            report.set_commit(commit);
+        }
+        self.reports.insert(commit.to_string(), report)
+    }
+
+    /// The report for `commit` if there is one
+    pub fn report(&self, commit: &str) -> Option<&CoverallsReport> {
+        self.reports.get(commit)
+    }
+
+    /// Commits in the batch in sorted order
+    pub fn commits(&self) -> impl Iterator<Item = &str> {
+        self.reports.keys().map(|c| c.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /// Uploads the reports one at a time to the jobs `url`. A failed upload
+    /// doesn't stop the rest, the result for each commit is returned.
+    pub fn upload(&mut self, url: &str) -> BTreeMap<String, Result<UploadStatus, CoverallsError>> {
+        self.reports
+            .iter_mut()
+            .map(|(commit, report)| (commit.clone(), report.upload_status_for(url)))
+            .collect()
+    }
+
+    /// Uploads the reports concurrently to the jobs `url` with a thread per
+    /// report, returning the result for each commit
+    pub fn upload_concurrent(
+        &mut self,
+        url: &str,
+    ) -> BTreeMap<String, Result<UploadStatus, CoverallsError>> {
+        std::thread::scope(|scope| {
+            let handles = self
+                .reports
+                .iter_mut()
+                .map(|(commit, report)| {
+                    (
+                        commit.clone(),
+                        scope.spawn(move || report.upload_status_for(url)),
+                    )
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|(commit, h)| (commit, h.join().expect("upload thread panicked")))
+                .collect()
+        })
+    }
+}
+
+/// Gets the URL of the coverage job from the response to a successful upload
+#[cfg(feature = "upload")]
+fn job_url(status: UploadStatus, response: &[u8]) -> Option<String> {
+    if status != UploadStatus::Succeeded {
+        return None;
+    }
+    serde_json::from_slice::<serde_json::Value>(response)
+        .ok()
+        .and_then(|v| v.get("url").and_then(|u| u.as_str()).map(String::from))
+}
+
+/// Gets the processing status of a job from the response to polling its JSON.
+/// The job is still being processed until it has coverage, failed polls say
+/// nothing about the upload so they leave it pending.
+#[cfg(feature = "upload")]
+fn job_status(code: u32, response: &[u8]) -> UploadStatus {
+    let processed = code == 200
+        && serde_json::from_slice::<serde_json::Value>(response)
+            .map(|v| v["covered_percent"].is_number())
+            .unwrap_or(false);
+    if processed {
+        UploadStatus::Succeeded
+    } else {
+        UploadStatus::Pending
+    }
+}
+
+/// Parses the delay in seconds from a `Retry-After` header, HTTP dates
+/// aren't supported
+#[cfg(feature = "upload")]
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+/// Gets the message from a coveralls response, using the whole body if it
+/// isn't JSON with a message field
+#[cfg(feature = "upload")]
+fn response_message(response: &[u8]) -> Option<String> {
+    let json = serde_json::from_slice::<serde_json::Value>(response).ok();
+    match json
+        .as_ref()
+        .and_then(|v| v.get("message"))
+        .and_then(|m| m.as_str())
+    {
+        Some(msg) => Some(msg.to_string()),
+        None => {
+            let body = String::from_utf8_lossy(response).trim().to_string();
+            if body.is_empty() {
+                None
+            } else {
+                Some(body)
+            }
+        }
+    }
+}
+
+/// Checks the jobs of a parallel build don't share a service job ID, which
+/// would make coveralls merge them into one job. Jobs without a job ID are
+/// ignored.
+pub fn validate_parallel(jobs: &[&CoverallsReport]) -> Result<(), CoverallsError> {
+    let mut seen = HashSet::new();
+    let ids = jobs.iter().filter_map(|r| match r.id {
+        Identity::ServiceToken(_, ref s) | Identity::GithubOidc(_, ref s) => s.job_id.as_deref(),
+        _ => None,
+    });
+    for id in ids {
+        if !seen.insert(id) {
+            return Err(CoverallsError::DuplicateJob(id.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the fields of the CI service to a serialized report
+fn serialize_service<M: SerializeMap>(s: &mut M, serv: &Service) -> Result<(), M::Error> {
+    s.serialize_entry("service_name", serv.name.value())?;
+    if let Some(ref id) = serv.job_id {
+        s.serialize_entry("service_job_id", id)?;
+    }
+    if let Some(ref num) = serv.number {
+        s.serialize_entry("service_number", &num)?;
+    }
+    if let Some(ref url) = serv.build_url {
+        s.serialize_entry("service_build_url", &url)?;
+    }
+    if let Some(ref branch) = serv.branch {
+        s.serialize_entry("service_branch", &branch)?;
+    }
+    if let Some(ref pr) = serv.pull_request {
+        s.serialize_entry("service_pull_request", &pr)?;
+    }
+    if let Some(ref base) = serv.base_branch {
+        s.serialize_entry("service_base_branch", &base)?;
+    }
+    if let Some(ref flag) = serv.flag_name {
+        s.serialize_entry("flag_name", &flag)?;
+    }
+    if let Some(ref attempt) = serv.attempt {
+        s.serialize_entry("service_attempt", &attempt)?;
+    }
+    Ok(())
+}
+
+impl Serialize for CoverallsReport {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // A map is used instead of a struct so the extra fields with
+        // non-static keys can be included
+        let mut s = serializer.serialize_map(None)?;
+        match self.id {
+            Identity::RepoToken(ref r) => {
+                s.serialize_entry("repo_token", &r)?;
+            }
+            Identity::GithubOidc(ref t, ref serv) => {
+                s.serialize_entry("oidc_token", t)?;
+                serialize_service(&mut s, serv)?;
+            }
+            Identity::ServiceToken(ref r, ref serv) => {
+                if !r.is_empty() {
+                    s.serialize_entry("repo_token", &r)?;
+                }
+                serialize_service(&mut s, serv)?;
+            }
+        }
+        if let Some(ref name) = self.repo_name {
+            s.serialize_entry("repo_name", &name)?;
+        }
+        if let Some(ref sha) = self.commit {
+            s.serialize_entry("commit_sha", &sha)?;
+        }
+        if let Some(ref git) = self.git {
+            s.serialize_entry("git", &git)?;
+        }
+        if let Some(ref base) = self.base_commit {
+            s.serialize_entry("base_commit", &base)?;
+        }
+        if let Some(ref run_at) = self.run_at {
+            s.serialize_entry("run_at", &run_at)?;
+        }
+        if let Some(ref diff) = self.diff {
+            s.serialize_entry("diff", &diff)?;
+        }
+        if self.parallel {
+            s.serialize_entry("parallel", &true)?;
+        }
+        let sources = self
+            .source_files
+            .iter()
+            .map(|x| SourceView::new(x, self))
+            .collect::<Vec<_>>();
+        s.serialize_entry("source_files", &sources)?;
+        for (key, value) in &self.extra_fields {
+            s.serialize_entry(key, value)?;
+        }
+        s.end()
+    }
+}
+
+#[cfg(all(test, feature = "upload"))]
+#[path = "../tests/common/mod.rs"]
+mod test_common;
+
+#[cfg(test)]
+mod tests {
+
+    #[cfg(feature = "upload")]
+    use crate::test_common::{read_request, serve_once};
+    use crate::*;
+    use std::collections::HashMap;
+    use std::env;
+    #[cfg(feature = "upload")]
+    use std::sync::Arc;
+    use std::sync::{Mutex, MutexGuard};
+    #[cfg(feature = "upload")]
+    use std::thread;
+
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Gets the headers of a raw HTTP request with lowercase names
+    #[cfg(feature = "upload")]
+    fn request_headers(request: &str) -> HashMap<String, String> {
+        let end = request.find("\r\n\r\n").unwrap_or(request.len());
+        request[..end]
+            .lines()
+            .skip(1)
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+            .collect()
+    }
+
+    /// Environment variables are process wide so tests which modify them
+    /// have to be serialised
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn test_expand_lines() {
+        let line_count = 10;
+        let mut example: HashMap<usize, usize> = HashMap::new();
+        example.insert(5, 1);
+        example.insert(6, 1);
+        example.insert(8, 2);
+
+        let expected = vec![
+            None,
+            None,
+            None,
+            None,
+            Some(1),
+            Some(1),
+            None,
+            Some(2),
+            None,
+            None,
+        ];
+
+        assert_eq!(expand_lines(&example, line_count), expected);
+    }
+
+    #[test]
+    fn test_branch_expand() {
+        let b1 = BranchData {
+            line_number: 3,
+            block_name: 1,
+            branch_number: 1,
+            hits: 1,
+        };
+        let b2 = BranchData {
+            line_number: 4,
+            block_name: 1,
+            branch_number: 2,
+            hits: 0,
+        };
+
+        let v = vec![b1, b2];
+        let actual = expand_branches(&v);
+        let expected = vec![3, 1, 1, 1, 4, 1, 2, 0];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_content_type() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        assert_eq!(report.content_type, DEFAULT_CONTENT_TYPE);
+        report.set_content_type("application/gzip");
+        assert_eq!(report.content_type, "application/gzip");
+    }
+
+    #[test]
+    fn test_new_relative() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let abs_path = root.join("tests/example/mysource.rs");
+        let lines = HashMap::new();
+
+        let source = Source::new_relative(root, &abs_path, &lines, &None, false).unwrap();
+        assert_eq!(source.name, "tests/example/mysource.rs");
+
+        let err =
+            Source::new_relative(&root.join("src"), &abs_path, &lines, &None, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_base_commit_serialization() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("base_commit").is_none());
+
+        report.set_commit("abcdef");
+        report.set_base_commit("123456");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["commit_sha"], "abcdef");
+        assert_eq!(json["base_commit"], "123456");
+    }
+
+    #[cfg(feature = "upload")]
+    struct MockTransport {
+        status: u32,
+        response: Vec<u8>,
+        posted: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    #[cfg(feature = "upload")]
+    impl Transport for MockTransport {
+        fn send(
+            &self,
+            _url: &str,
+            body: Vec<u8>,
+            _content_type: &str,
+            _compressed: bool,
+        ) -> Result<(u32, Vec<u8>), CoverallsError> {
+            self.posted.lock().unwrap().push(body);
+            Ok((self.status, self.response.clone()))
+        }
+
+        fn get(&self, _url: &str) -> Result<(u32, Vec<u8>), CoverallsError> {
+            Ok((self.status, self.response.clone()))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_mock_transport() {
+        let posted = Arc::new(Mutex::new(vec![]));
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: vec![],
+            posted: posted.clone(),
+        }));
+
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+
+        let posted = posted.lock().unwrap();
+        assert_eq!(posted.len(), 1);
+        let expected = gzip(&serde_json::to_vec(&report).unwrap()).unwrap();
+        assert_eq!(posted[0], expected);
+    }
+
+    #[test]
+    fn test_github_base_branch() {
+        let _lock = lock_env();
+        env::set_var("GITHUB_RUN_ID", "1234");
+        env::set_var("GITHUB_REF", "refs/pull/42/merge");
+        env::set_var("GITHUB_HEAD_REF", "feature");
+        env::set_var("GITHUB_BASE_REF", "main");
+
+        let service = Service::get_github_actions_env();
+
+        env::remove_var("GITHUB_RUN_ID");
+        env::remove_var("GITHUB_REF");
+        env::remove_var("GITHUB_HEAD_REF");
+        env::remove_var("GITHUB_BASE_REF");
+
+        assert_eq!(service.name, CiService::GithubActions);
+        assert_eq!(service.job_id, Some("1234".to_string()));
+        assert_eq!(service.pull_request, Some("42".to_string()));
+        assert_eq!(service.branch, Some("feature".to_string()));
+        assert_eq!(service.base_branch, Some("main".to_string()));
+
+        let report = CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), service));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_name"], "github");
+        assert_eq!(json["service_base_branch"], "main");
+    }
+
+    #[test]
+    fn test_from_lcov_record() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let da = vec![(2, 1), (3, 0), (2, 2)];
+        let brda = vec![BranchData {
+            line_number: 3,
+            block_name: 0,
+            branch_number: 1,
+            hits: 0,
+        }];
+
+        let source = Source::from_lcov_record(repo_path, &path, &da, &brda, false).unwrap();
+        assert_eq!(source.coverage[1], Some(3));
+        assert_eq!(source.coverage[2], Some(0));
+        assert_eq!(source.coverage[0], None);
+        assert_eq!(source.branches, Some(vec![3, 0, 1, 0]));
+
+        let source = Source::from_lcov_record(repo_path, &path, &da, &[], false).unwrap();
+        assert_eq!(source.branches, None);
+
+        let da = vec![(2, usize::MAX), (2, 1)];
+        let source = Source::from_lcov_record(repo_path, &path, &da, &[], false).unwrap();
+        assert_eq!(source.coverage[1], Some(usize::MAX));
+    }
+
+    #[test]
+    fn test_extra_fields() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_extra_field("flag_name", serde_json::json!("unit"));
+        report.set_extra_field("custom", serde_json::json!({"a": 1}));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repo_token"], "token");
+        assert_eq!(json["flag_name"], "unit");
+        assert_eq!(json["custom"]["a"], 1);
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_http_version() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        assert_eq!(report.http_options.version, HttpVersion::Auto);
+        report.set_http_version(HttpVersion::Http2);
+        assert_eq!(report.http_options.version, HttpVersion::Http2);
+        assert!(HttpTransport::with_options(&report.http_options).is_ok());
+    }
+
+    #[test]
+    fn test_report_from_env() {
+        let _lock = lock_env();
+        env::set_var("COVERALLS_REPO_TOKEN", "secret");
+        env::set_var("GITHUB_SHA", "abc123");
+
+        let report = CoverallsReport::from_env();
+
+        env::remove_var("COVERALLS_REPO_TOKEN");
+        env::remove_var("GITHUB_SHA");
+
+        let report = report.unwrap();
+        assert_eq!(report.commit, Some("abc123".to_string()));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repo_token"], "secret");
+        assert_eq!(json["commit_sha"], "abc123");
+    }
+
+    #[test]
+    fn test_source_accessors() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let source = Source::new(repo_path, &path, &HashMap::new(), &None, false).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(source.name(), "tests/example/mysource.rs");
+        assert_eq!(source.digest(), format!("{:x}", md5::compute(&content)));
+        assert_eq!(source.line_count(), content.lines().count());
+    }
+
+    #[test]
+    fn test_github_matrix_legs() {
+        let _lock = lock_env();
+        env::set_var("GITHUB_RUN_ID", "1234");
+        env::set_var("GITHUB_RUN_ATTEMPT", "2");
+
+        env::set_var("GITHUB_JOB", "test-linux");
+        let linux = Service::get_github_actions_env();
+        env::set_var("GITHUB_JOB", "test-macos");
+        let macos = Service::get_github_actions_env();
+
+        env::remove_var("GITHUB_RUN_ID");
+        env::remove_var("GITHUB_RUN_ATTEMPT");
+        env::remove_var("GITHUB_JOB");
+
+        assert_eq!(linux.number, Some("1234-2".to_string()));
+        assert_eq!(linux.number, macos.number);
+        assert_eq!(linux.flag_name, Some("test-linux".to_string()));
+        assert_eq!(macos.flag_name, Some("test-macos".to_string()));
+
+        let report = CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), linux));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_number"], "1234-2");
+        assert_eq!(json["flag_name"], "test-linux");
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_send_returns_url() {
+        let response = br#"{"message":"Job #1.1","url":"https://coveralls.io/jobs/1"}"#;
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: response.to_vec(),
+            posted: Arc::new(Mutex::new(vec![])),
+        }));
+        let url = report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(url, Some("https://coveralls.io/jobs/1".to_string()));
+
+        report.set_transport(Box::new(MockTransport {
+            status: 422,
+            response: response.to_vec(),
+            posted: Arc::new(Mutex::new(vec![])),
+        }));
+        let url = report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(url, None);
+        assert_eq!(report.upload_status(), UploadStatus::Failed(422));
+    }
+
+    #[test]
+    fn test_add_remote_dedup() {
+        let origin = Remote {
+            name: "origin".to_string(),
+            url: "https://github.com/xd009642/coveralls-api".to_string(),
+        };
+        let fork = Remote {
+            name: "fork".to_string(),
+            url: "https://github.com/someone/coveralls-api".to_string(),
+        };
+        let mut git = GitInfo::default();
+        git.add_remote(origin.clone());
+        git.add_remote(fork.clone());
+        git.add_remote(origin.clone());
+        assert_eq!(git.remotes, vec![origin, fork]);
+    }
+
+    #[test]
+    fn test_exclude_branches() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let branches = Some(vec![BranchData {
+            line_number: 2,
+            block_name: 0,
+            branch_number: 0,
+            hits: 1,
+        }]);
+        let source = Source::new(repo_path, &path, &HashMap::new(), &branches, false).unwrap();
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(source);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["source_files"][0]["branches"],
+            serde_json::json!([2, 0, 0, 1])
+        );
+
+        report.set_include_branches(false);
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["source_files"][0].get("branches").is_none());
+        assert!(json["source_files"][0].get("coverage").is_some());
+    }
+
+    #[test]
+    fn test_cirrus_env() {
+        let _lock = lock_env();
+        env::set_var("CIRRUS_TASK_ID", "5678");
+        env::set_var("CIRRUS_BUILD_ID", "1234");
+        env::set_var("CIRRUS_BRANCH", "master");
+        env::remove_var("CIRRUS_PR");
+
+        let service = Service::get_cirrus_env();
+
+        env::remove_var("CIRRUS_TASK_ID");
+        env::remove_var("CIRRUS_BUILD_ID");
+        env::remove_var("CIRRUS_BRANCH");
+
+        assert_eq!(service.name, CiService::Cirrus);
+        assert_eq!(service.job_id, Some("5678".to_string()));
+        assert_eq!(service.number, Some("1234".to_string()));
+        assert_eq!(service.branch, Some("master".to_string()));
+        assert_eq!(service.pull_request, None);
+    }
+
+    #[test]
+    fn test_sort_sources() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        for name in &["src/c.rs", "src/a.rs", "src/b.rs"] {
+            report.add_source(Source {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        }
+        report.sort_sources();
+        let names = report
+            .source_files
+            .iter()
+            .map(|x| x.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["src/a.rs", "src/b.rs", "src/c.rs"]);
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_http_method() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        assert_eq!(report.http_options.method, HttpMethod::Post);
+        report.set_http_method(HttpMethod::Put);
+        assert_eq!(report.http_options.method, HttpMethod::Put);
+
+        let transport = HttpTransport::with_options(&report.http_options).unwrap();
+        assert_eq!(transport.method, HttpMethod::Put);
+    }
+
+    #[test]
+    #[cfg(feature = "rustls-tls")]
+    fn test_rustls_client() {
+        assert!(Client::builder().use_rustls_tls().build().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "native-tls")]
+    fn test_native_tls_client() {
+        assert!(Client::builder().use_native_tls().build().is_ok());
+    }
+
+    #[test]
+    fn test_digest_bytes() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let source = Source::new(repo_path, &path, &HashMap::new(), &None, false).unwrap();
+
+        let bytes = source.digest_bytes();
+        assert_eq!(bytes.len(), 16);
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        assert_eq!(source.digest(), hex);
+
+        let json = serde_json::to_value(&source).unwrap();
+        assert_eq!(json["source_digest"], hex);
+        let round_trip: Source = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip, source);
+    }
+
+    #[test]
+    fn test_run_at_serialization() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("run_at").is_none());
+
+        report.set_run_at("2019-02-12T08:48:27+00:00");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["run_at"], "2019-02-12T08:48:27+00:00");
+    }
+
+    #[test]
+    fn test_filter_unchanged() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(Source {
+            name: "src/unchanged.rs".to_string(),
+            source_digest: [1; 16],
+            ..Default::default()
+        });
+        report.add_source(Source {
+            name: "src/changed.rs".to_string(),
+            source_digest: [2; 16],
+            ..Default::default()
+        });
+
+        let mut known = HashMap::new();
+        known.insert("src/unchanged.rs".to_string(), "01".repeat(16));
+        known.insert("src/changed.rs".to_string(), "03".repeat(16));
+        report.filter_unchanged(&known);
+
+        assert_eq!(report.source_files.len(), 1);
+        assert_eq!(report.source_files[0].name(), "src/changed.rs");
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_send_chunked() {
+        let service = Service {
+            name: CiService::Travis,
+            job_id: Some("1".to_string()),
+            number: Some("42".to_string()),
+            build_url: None,
+            branch: None,
+            pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        };
+        let mut report =
+            CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), service));
+        for i in 0..5 {
+            report.add_source(Source {
+                name: format!("src/{}.rs", i),
+                ..Default::default()
+            });
+        }
+        let posted = Arc::new(Mutex::new(vec![]));
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: vec![],
+            posted: posted.clone(),
+        }));
+
+        let status = report
+            .send_chunked("https://coveralls.io/api/v1/jobs", 2)
+            .unwrap();
+        assert_eq!(status, UploadStatus::Succeeded);
+        assert_eq!(report.source_files.len(), 5);
+        assert!(!report.parallel);
+
+        let posted = posted.lock().unwrap();
+        // 3 chunks of jobs and then the done webhook
+        assert_eq!(posted.len(), 4);
+        let job_ids = posted[..3]
+            .iter()
+            .map(|body| {
+                let mut json = vec![];
+                GzDecoder::new(&body[..]).read_to_end(&mut json).unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+                json["service_job_id"].clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(job_ids, vec!["1-1", "1-2", "1-3"]);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_job_id"], "1");
+        let done: serde_json::Value = serde_json::from_slice(&posted[3]).unwrap();
+        assert_eq!(done["payload"]["build_num"], "42");
+        assert_eq!(done["payload"]["status"], "done");
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_send_chunked_requires_number() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let res = report.send_chunked("https://coveralls.io/api/v1/jobs", 2);
+        assert!(matches!(res, Err(CoverallsError::MissingServiceNumber)));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_endpoint_urls() {
+        let io = CoverallsEndpoint::CoverallsIo;
+        assert_eq!(io.jobs_url(), "https://coveralls.io/api/v1/jobs");
+        assert_eq!(io.webhook_url(), "https://coveralls.io/webhook");
+
+        let enterprise = CoverallsEndpoint::Enterprise(
+            "https://coveralls.example.com/".to_string(),
+            JobsPath::V1,
+        );
+        assert_eq!(
+            enterprise.jobs_url(),
+            "https://coveralls.example.com/api/v1/jobs"
+        );
+        assert_eq!(
+            enterprise.webhook_url(),
+            "https://coveralls.example.com/webhook"
+        );
+
+        assert_eq!(
+            CoverallsEndpoint::from_jobs_url("https://coveralls.io/api/v1/jobs").webhook_url(),
+            "https://coveralls.io/webhook"
+        );
+
+        let unversioned = CoverallsEndpoint::Enterprise(
+            "https://coveralls.example.com".to_string(),
+            JobsPath::Unversioned,
+        );
+        assert_eq!(
+            unversioned.jobs_url(),
+            "https://coveralls.example.com/api/jobs"
+        );
+        assert_eq!(
+            unversioned.webhook_url(),
+            "https://coveralls.example.com/webhook"
+        );
+        assert_eq!(
+            CoverallsEndpoint::from_jobs_url("https://coveralls.example.com/api/jobs"),
+            unversioned
+        );
+        assert_eq!(
+            CoverallsEndpoint::from_jobs_url("https://coveralls.example.com/api/jobs/?token=a"),
+            unversioned
+        );
+        assert_eq!(
+            CoverallsEndpoint::from_jobs_url("https://coveralls.example.com/ci/v1/jobs?token=a")
+                .webhook_url(),
+            "https://coveralls.example.com/webhook"
+        );
+        assert_eq!(
+            CoverallsEndpoint::from_jobs_url("http://127.0.0.1:8080/coveralls/api/v1/jobs")
+                .webhook_url(),
+            "http://127.0.0.1:8080/coveralls/webhook"
+        );
+    }
+
+    #[test]
+    fn test_git_info_from_env() {
+        let event = env::temp_dir().join(format!(
+            "coveralls_api_git_info_event_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &event,
+            r#"{"head_commit": {
+                "message": "Fix things",
+                "author": {"name": "Author", "email": "author@example.com"},
+                "committer": {"name": "Committer", "email": "committer@example.com"}
+            }}"#,
+        )
+        .unwrap();
+
+        let _lock = lock_env();
+        env::remove_var("GITHUB_SHA");
+        env::remove_var("GITHUB_HEAD_REF");
+        assert!(GitInfo::from_env().is_none());
+
+        env::set_var("GITHUB_SHA", "abc123");
+        env::set_var("GITHUB_REF", "refs/heads/master");
+        env::set_var("GITHUB_EVENT_PATH", &event);
+        env::set_var("GITHUB_SERVER_URL", "https://github.com");
+        env::set_var("GITHUB_REPOSITORY", "xd009642/coveralls-api");
+
+        let git = GitInfo::from_env();
+
+        env::remove_var("GITHUB_SHA");
+        env::remove_var("GITHUB_REF");
+        env::remove_var("GITHUB_EVENT_PATH");
+        env::remove_var("GITHUB_SERVER_URL");
+        env::remove_var("GITHUB_REPOSITORY");
+        let _ = std::fs::remove_file(&event);
+
+        let git = git.unwrap();
+        assert_eq!(git.head.id, "abc123");
+        assert_eq!(git.head.author_name, "Author");
+        assert_eq!(git.head.author_email, "author@example.com");
+        assert_eq!(git.head.committer_name, "Committer");
+        assert_eq!(git.head.committer_email, "committer@example.com");
+        assert_eq!(git.head.message, "Fix things");
+        assert_eq!(git.branch, "master");
+        assert_eq!(
+            git.remotes[0].url,
+            "https://github.com/xd009642/coveralls-api.git"
+        );
+    }
+
+    #[test]
+    fn test_source_coverage_predicates() {
+        let empty = Source {
+            coverage: vec![None, None],
+            ..Default::default()
+        };
+        assert!(!empty.is_covered());
+        assert!(!empty.has_coverage_data());
+
+        let uncovered = Source {
+            coverage: vec![None, Some(0), Some(0)],
+            ..Default::default()
+        };
+        assert!(!uncovered.is_covered());
+        assert!(uncovered.has_coverage_data());
+
+        let partial = Source {
+            coverage: vec![None, Some(0), Some(3)],
+            ..Default::default()
+        };
+        assert!(partial.is_covered());
+        assert!(partial.has_coverage_data());
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_custom_headers() {
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_header("Authorization", "Bearer secret");
+        report.add_header("X-Proxy-Token", "12345");
+
+        report.send_to_endpoint(&url).unwrap();
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+
+        let request = server.join().unwrap().to_lowercase();
+        assert!(request.contains("authorization: bearer secret"));
+        assert!(request.contains("x-proxy-token: 12345"));
+
+        report.add_header("Bad Header", "value");
+        let res = report.send_to_endpoint(&url);
+        assert!(matches!(res, Err(CoverallsError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_parallel_status_serialization() {
+        let json = serde_json::to_value(ParallelStatus::Done).unwrap();
+        assert_eq!(json, "done");
+        let status: ParallelStatus = serde_json::from_str("\"done\"").unwrap();
+        assert_eq!(status, ParallelStatus::Done);
+    }
+
+    #[test]
+    fn test_branch_data_round_trip() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let branches = vec![
+            BranchData {
+                line_number: 2,
+                block_name: 0,
+                branch_number: 0,
+                hits: 1,
+            },
+            BranchData {
+                line_number: 2,
+                block_name: 0,
+                branch_number: 1,
+                hits: 0,
+            },
+        ];
+        let source = Source::new(
+            repo_path,
+            &path,
+            &HashMap::new(),
+            &Some(branches.clone()),
+            false,
+        )
+        .unwrap();
+
+        let json = serde_json::to_string(&source).unwrap();
+        let source: Source = serde_json::from_str(&json).unwrap();
+        assert_eq!(source.branch_data(), Some(branches));
+
+        let source = Source::new(repo_path, &path, &HashMap::new(), &None, false).unwrap();
+        assert_eq!(source.branch_data(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_endpoint_from_env() {
+        let _lock = lock_env();
+        env::remove_var("COVERALLS_ENDPOINT");
+        assert_eq!(
+            CoverallsEndpoint::from_env(),
+            CoverallsEndpoint::CoverallsIo
+        );
+
+        env::set_var("COVERALLS_ENDPOINT", "https://coveralls.example.com/");
+        let endpoint = CoverallsEndpoint::from_env();
+        env::remove_var("COVERALLS_ENDPOINT");
+        assert_eq!(
+            endpoint.jobs_url(),
+            "https://coveralls.example.com/api/v1/jobs"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_upload_result_message() {
+        let response =
+            br#"{"message":"Couldn't find a repository matching this job.","error":true}"#;
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(MockTransport {
+            status: 422,
+            response: response.to_vec(),
+            posted: Arc::new(Mutex::new(vec![])),
+        }));
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+
+        match report.upload_result() {
+            Err(CoverallsError::UploadFailed(422, Some(msg))) => {
+                assert_eq!(msg, "Couldn't find a repository matching this job.");
+            }
+            e => panic!("Unexpected result {:?}", e),
+        }
+
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: vec![],
+            posted: Arc::new(Mutex::new(vec![])),
+        }));
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(report.upload_result().unwrap(), UploadStatus::Succeeded);
+    }
+
+    #[test]
+    fn test_service_attempt() {
+        let _lock = lock_env();
+        env::set_var("GITHUB_RUN_ID", "1234");
+        env::set_var("GITHUB_RUN_ATTEMPT", "3");
+
+        let service = Service::get_github_actions_env();
+
+        env::remove_var("GITHUB_RUN_ID");
+        env::remove_var("GITHUB_RUN_ATTEMPT");
+
+        assert_eq!(service.attempt, Some("3".to_string()));
+        let report = CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), service));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_attempt"], "3");
+    }
+
+    #[test]
+    fn test_retain_sources() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        for name in &["src/lib.rs", "src/bin/main.rs", "tests/test.rs"] {
+            report.add_source(Source {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        }
+        report.retain_sources(|x| x.name().starts_with("src/"));
+        assert_eq!(report.source_files.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "glob")]
+    fn test_retain_by_glob() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        for name in &["src/lib.rs", "src/bin/main.rs", "tests/test.rs"] {
+            report.add_source(Source {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        }
+        report.retain_by_glob("src/bin/*.rs").unwrap();
+        assert_eq!(report.source_files.len(), 1);
+        assert_eq!(report.source_files[0].name(), "src/bin/main.rs");
+
+        assert!(report.retain_by_glob("src/[").is_err());
+    }
+
+    #[test]
+    fn test_sourcehut_env() {
+        let _lock = lock_env();
+        env::set_var("JOB_ID", "1234");
+        env::set_var("JOB_URL", "https://builds.sr.ht/~user/job/1234");
+        env::set_var("GIT_REF", "refs/heads/master");
+
+        let service = Service::get_sourcehut_env();
+
+        env::remove_var("JOB_ID");
+        env::remove_var("JOB_URL");
+        env::remove_var("GIT_REF");
+
+        assert_eq!(service.name, CiService::Sourcehut);
+        assert_eq!(service.job_id, Some("1234".to_string()));
+        assert_eq!(
+            service.build_url,
+            Some("https://builds.sr.ht/~user/job/1234".to_string())
+        );
+        assert_eq!(service.branch, Some("master".to_string()));
+    }
+
+    #[test]
+    fn test_set_service_number() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_service_number("99");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repo_token"], "token");
+        assert_eq!(json["service_number"], "99");
+
+        report.set_service_number("100");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_number"], "100");
+    }
+
+    #[test]
+    fn test_covered_uncovered_lines() {
+        let source = Source {
+            coverage: vec![None, Some(1), Some(0), None, Some(5), Some(0)],
+            ..Default::default()
+        };
+        assert_eq!(source.covered_lines().collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(source.uncovered_lines().collect::<Vec<_>>(), vec![3, 6]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_new_relative_symlink() {
+        let tmp = env::temp_dir().join(format!("coveralls_api_symlink_{}", std::process::id()));
+        let real = tmp.join("real");
+        let link = tmp.join("link");
+        std::fs::create_dir_all(real.join("src")).unwrap();
+        std::fs::write(real.join("src/lib.rs"), "fn main() {}\n").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let lines = HashMap::new();
+        let through_link =
+            Source::new_relative(&real, &link.join("src/lib.rs"), &lines, &None, false);
+        let through_root =
+            Source::new_relative(&link, &real.join("src/lib.rs"), &lines, &None, false);
+        let _ = std::fs::remove_dir_all(&tmp);
+
+        assert_eq!(through_link.unwrap().name(), "src/lib.rs");
+        assert_eq!(through_root.unwrap().name(), "src/lib.rs");
+    }
+
+    #[test]
+    fn test_generic_env_fallback_name() {
+        let _lock = lock_env();
+        let vars = [
+            "CI_NAME",
+            "CI_BUILD_NUMBER",
+            "CI_JOB_ID",
+            "CI_BUILD_URL",
+            "CI_BRANCH",
+            "CI_PULL_REQUEST",
+        ];
+        let saved = vars.iter().map(|v| env::var(v).ok()).collect::<Vec<_>>();
+        for v in &vars {
+            env::remove_var(v);
+        }
+        env::set_var("CI_BUILD_NUMBER", "12");
+
+        let service = Service::get_generic_env();
+
+        for (v, val) in vars.iter().zip(saved) {
+            match val {
+                Some(val) => env::set_var(v, val),
+                None => env::remove_var(v),
+            }
+        }
+
+        let service = service.unwrap();
+        assert_eq!(service.name, CiService::Other("coveralls-ruby".to_string()));
+        assert_eq!(service.number, Some("12".to_string()));
+    }
+
+    #[test]
+    fn test_max_embedded_source() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(Source {
+            name: "src/big.rs".to_string(),
+            source: Some("a".repeat(100)),
+            ..Default::default()
+        });
+        report.add_source(Source {
+            name: "src/small.rs".to_string(),
+            source: Some("a".repeat(10)),
+            ..Default::default()
+        });
+        report.set_max_embedded_source_bytes(50);
+
+        assert_eq!(report.omitted_sources(), vec!["src/big.rs"]);
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["source_files"][0].get("source").is_none());
+        assert!(json["source_files"][0].get("source_digest").is_some());
+        assert_eq!(json["source_files"][1]["source"], "a".repeat(10));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_self_check() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![None, Some(1), Some(0)],
+            source: Some("fn main() {\n    println!(\"✓\");\n}\n".to_string()),
+            ..Default::default()
+        });
+        report.set_commit("abc123");
+        assert!(report.self_check().is_ok());
+        report.set_compression(Compression::Uncompressed);
+        assert!(report.self_check().is_ok());
+    }
+
+    #[test]
+    fn test_identity_redacted() {
+        let report = CoverallsReport::new(Identity::RepoToken("secret-token".into()));
+        let debug = report.identity().redacted_debug();
+        assert!(!debug.contains("secret-token"));
+        assert_eq!(debug, "RepoToken(\"****\")");
+
+        let service = Service::get_travis_env();
+        let id = Identity::ServiceToken("secret-token".into(), service);
+        let debug = id.redacted_debug();
+        assert!(!debug.contains("secret-token"));
+        assert!(debug.starts_with("ServiceToken(\"****\", Service {"));
+    }
+
+    #[test]
+    fn test_harness_env() {
+        let _lock = lock_env();
+        env::set_var("HARNESS_BUILD_ID", "77");
+        env::set_var("DRONE_BUILD_LINK", "https://app.harness.io/builds/77");
+        env::set_var("DRONE_COMMIT_BRANCH", "develop");
+        env::set_var("DRONE_PULL_REQUEST", "15");
+
+        let service = Service::get_harness_env();
+
+        env::remove_var("HARNESS_BUILD_ID");
+        env::remove_var("DRONE_BUILD_LINK");
+        env::remove_var("DRONE_COMMIT_BRANCH");
+        env::remove_var("DRONE_PULL_REQUEST");
+
+        assert_eq!(service.name, CiService::Harness);
+        assert_eq!(service.number, Some("77".to_string()));
+        assert_eq!(
+            service.build_url,
+            Some("https://app.harness.io/builds/77".to_string())
+        );
+        assert_eq!(service.branch, Some("develop".to_string()));
+        assert_eq!(service.pull_request, Some("15".to_string()));
+    }
+
+    #[test]
+    fn test_from_env_ordered_precedence() {
+        let _lock = lock_env();
+        env::set_var("JENKINS_URL", "http://jenkins.local/");
+        env::set_var("GITHUB_ACTIONS", "true");
+        env::set_var("GITHUB_RUN_ID", "42");
+        let jenkins_first =
+            Service::from_env_ordered(&[CiService::Jenkins, CiService::GithubActions]);
+        let github_first =
+            Service::from_env_ordered(&[CiService::GithubActions, CiService::Jenkins]);
+        env::remove_var("JENKINS_URL");
+        env::remove_var("GITHUB_ACTIONS");
+        env::remove_var("GITHUB_RUN_ID");
+
+        assert_eq!(jenkins_first.unwrap().name, CiService::Jenkins);
+        assert_eq!(github_first.unwrap().name, CiService::GithubActions);
+    }
+
+    #[test]
+    fn test_source_comment_only_when_set() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            ..Default::default()
+        });
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["source_files"][0].get("comment").is_none());
+
+        report.source_files[0].set_comment("generated code excluded");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["source_files"][0]["comment"],
+            "generated code excluded"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "glob")]
+    fn test_apply_ignore_file() {
+        let ignore = env::temp_dir().join(format!("coveralls_api_ignore_{}", std::process::id()));
+        std::fs::write(&ignore, "# generated code\nsrc/gen/*.rs\n\ntests/**\n").unwrap();
+
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        for name in &["src/lib.rs", "src/gen/bindings.rs", "tests/common/mod.rs"] {
+            report.add_source(Source {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        }
+        let res = report.apply_ignore_file(&ignore);
+        let _ = std::fs::remove_file(&ignore);
+        res.unwrap();
+
+        assert_eq!(report.source_files.len(), 1);
+        assert_eq!(report.source_files[0].name(), "src/lib.rs");
+    }
+
+    #[test]
+    fn test_commit_and_git_accessors() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        assert!(report.commit().is_none());
+        assert!(report.git().is_none());
+
+        report.set_commit("abc123");
+        assert_eq!(report.commit(), Some("abc123"));
+        assert!(report.git().is_none());
+
+        let git = GitInfo {
+            head: Head {
+                id: "def456".to_string(),
+                ..Default::default()
+            },
+            branch: "master".to_string(),
+            remotes: vec![],
+            shallow: false,
+        };
+        report.set_detailed_git_info(git.clone());
+        assert!(report.commit().is_none());
+        assert_eq!(report.git(), Some(&git));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_gzip_as_content_encoding() {
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_gzip_as_content_encoding(true);
+
+        report.send_to_endpoint(&url).unwrap();
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+
+        let request = server.join().unwrap();
+        let (payload, _, _) = report.payload().unwrap();
+        let headers = request_headers(&request);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(headers["content-length"], payload.len().to_string());
+
+        // The gzipped body is still marked as such with a JSON content type
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        report.set_content_type("application/json");
+        report.send_to_endpoint(&url).unwrap();
+        let headers = request_headers(&server.join().unwrap());
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["content-encoding"], "gzip");
+
+        // Without it the report is a multipart form part
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        report.set_gzip_as_content_encoding(false);
+        report.send_to_endpoint(&url).unwrap();
+        let headers = request_headers(&server.join().unwrap());
+        assert!(headers["content-type"].starts_with("multipart/form-data"));
+        assert!(!headers.contains_key("content-encoding"));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_estimated_payload_size() {
+        let content = (0..1000)
+            .map(|i| format!("let x{} = {};\n", i, i * 7919 % 1013))
+            .collect::<String>();
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(1); 1000],
+            ..Default::default()
+        });
+        let without_source = report.estimated_payload_size().unwrap();
+        assert_eq!(
+            without_source,
+            gzip(&report.to_json().unwrap()).unwrap().len()
+        );
+
+        report.source_files[0].source = Some(content);
+        let with_source = report.estimated_payload_size().unwrap();
+        assert!(with_source > without_source);
+
+        report.set_compression(Compression::Uncompressed);
+        let uncompressed = report.estimated_payload_size().unwrap();
+        assert_eq!(uncompressed, report.to_json().unwrap().len());
+        assert!(uncompressed > with_source);
+    }
+
+    #[test]
+    fn test_exclude_lines() {
+        let mut source = Source {
+            coverage: vec![Some(1), Some(0), Some(2), Some(0), None],
+            ..Default::default()
+        };
+        source.exclude_lines(2..=3);
+        assert_eq!(source.coverage, vec![Some(1), None, None, Some(0), None]);
+
+        source.exclude_lines(4..=100);
+        assert_eq!(source.coverage, vec![Some(1), None, None, None, None]);
+    }
+
+    #[test]
+    fn test_branch_data_from_cobertura() {
+        let branches = BranchData::from_cobertura(12, 2, 1);
+        assert_eq!(
+            branches,
+            vec![
+                BranchData {
+                    line_number: 12,
+                    block_name: 0,
+                    branch_number: 0,
+                    hits: 1,
+                },
+                BranchData {
+                    line_number: 12,
+                    block_name: 0,
+                    branch_number: 1,
+                    hits: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "upload"))]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let path = env::temp_dir().join(format!("coveralls_api_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_unix_socket(&path);
+        assert_eq!(report.http_options.unix_socket, Some(path.clone()));
+
+        let res = report.send_to_endpoint("http://coveralls.local/api/v1/jobs");
+        let _ = std::fs::remove_file(&path);
+        res.unwrap();
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+
+        let request = server.join().unwrap().to_lowercase();
+        assert!(request.starts_with("post /api/v1/jobs"));
+        assert!(request.contains("host: coveralls.local"));
+    }
+
+    #[test]
+    fn test_has_embedded_source() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let lines = HashMap::new();
+
+        let source = Source::new(repo_path, &path, &lines, &None, true).unwrap();
+        assert!(source.has_embedded_source());
+
+        let source = Source::new(repo_path, &path, &lines, &None, false).unwrap();
+        assert!(!source.has_embedded_source());
+    }
+
+    #[test]
+    fn test_validate_parallel() {
+        let job = |id: &str| {
+            let service = Service {
+                name: CiService::Travis,
+                job_id: Some(id.to_string()),
+                number: Some("42".to_string()),
+                build_url: None,
+                branch: None,
+                pull_request: None,
+                base_branch: None,
+                flag_name: None,
+                attempt: None,
+            };
+            CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), service))
+        };
+        let (a, b, c) = (job("1"), job("2"), job("1"));
+        let untracked = CoverallsReport::new(Identity::RepoToken("token".into()));
+
+        assert!(validate_parallel(&[&a, &b, &untracked]).is_ok());
+        let res = validate_parallel(&[&a, &b, &c]);
+        assert!(matches!(res, Err(CoverallsError::DuplicateJob(ref id)) if id == "1"));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_add_resolve() {
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let port = url
+            .trim_start_matches("http://127.0.0.1:")
+            .split('/')
+            .next()
+            .and_then(|p| p.parse::<u16>().ok())
+            .unwrap();
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_resolve("coveralls.internal", port, "127.0.0.1");
+        assert_eq!(
+            report.http_options.resolve,
+            vec![(
+                "coveralls.internal".to_string(),
+                port,
+                "127.0.0.1".to_string()
+            )]
+        );
+
+        let url = format!("http://coveralls.internal:{}/api/v1/jobs", port);
+        report.send_to_endpoint(&url).unwrap();
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+        let request = server.join().unwrap().to_lowercase();
+        assert!(request.contains(&format!("host: coveralls.internal:{}", port)));
+
+        report.add_resolve("coveralls.internal", port, "not an ip");
+        let res = report.send_to_endpoint(&url);
+        assert!(matches!(res, Err(CoverallsError::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn test_merge_line_hits() {
+        let a: HashMap<usize, usize> = [(1, 2), (2, 0), (5, 1)].iter().cloned().collect();
+        let b: HashMap<usize, usize> = [(2, 3), (5, 1), (7, 0)].iter().cloned().collect();
+        let merged = merge_line_hits(&[&a, &b]);
+        let expected: HashMap<usize, usize> =
+            [(1, 2), (2, 3), (5, 2), (7, 0)].iter().cloned().collect();
+        assert_eq!(merged, expected);
+
+        let huge: HashMap<usize, usize> = [(1, usize::MAX)].iter().cloned().collect();
+        let merged = merge_line_hits(&[&huge, &a]);
+        assert_eq!(merged[&1], usize::MAX);
+    }
+
+    #[test]
+    fn test_repo_name_serialization() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("repo_name").is_none());
+
+        report.set_repo_name("xd009642/coveralls-api");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repo_name"], "xd009642/coveralls-api");
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_retry_after_header() {
+        assert_eq!(parse_retry_after(" 2 "), Some(Duration::from_secs(2)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+
+        let (url, server) = serve_once(
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 2\r\nContent-Length: 0\r\n\r\n",
+        );
+        let (code, _, retry_after) = HttpTransport::new()
+            .send_with_retry_after(&url, vec![], DEFAULT_CONTENT_TYPE, true)
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(code, 429);
+        assert_eq!(retry_after, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_send_with_retries() {
+        struct RateLimited {
+            attempts: Arc<Mutex<usize>>,
+            retry_after: Duration,
+        }
+
+        impl Transport for RateLimited {
+            fn send(
+                &self,
+                _: &str,
+                _: Vec<u8>,
+                _: &str,
+                _: bool,
+            ) -> Result<(u32, Vec<u8>), CoverallsError> {
+                unreachable!()
+            }
+
+            fn send_with_retry_after(
+                &self,
+                _url: &str,
+                _body: Vec<u8>,
+                _content_type: &str,
+                _compressed: bool,
+            ) -> Result<(u32, Vec<u8>, Option<Duration>), CoverallsError> {
+                let mut attempts = self.attempts.lock().unwrap();
+                *attempts += 1;
+                if *attempts == 1 {
+                    Ok((429, vec![], Some(self.retry_after)))
+                } else {
+                    Ok((
+                        200,
+                        br#"{"url":"https://coveralls.io/jobs/1"}"#.to_vec(),
+                        None,
+                    ))
+                }
+            }
+        }
+
+        let attempts = Arc::new(Mutex::new(0));
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(RateLimited {
+            attempts: attempts.clone(),
+            retry_after: Duration::from_secs(1),
+        }));
+
+        let start = std::time::Instant::now();
+        let url = report
+            .send_with_retries("https://coveralls.io/api/v1/jobs", 3)
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        assert_eq!(url, Some("https://coveralls.io/jobs/1".to_string()));
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+
+        // A day long Retry-After is capped by the maximum wait
+        let attempts = Arc::new(Mutex::new(0));
+        report.set_transport(Box::new(RateLimited {
+            attempts: attempts.clone(),
+            retry_after: Duration::from_secs(86400),
+        }));
+        report.set_max_retry_wait(Duration::from_millis(10));
+        let start = std::time::Instant::now();
+        report
+            .send_with_retries("https://coveralls.io/api/v1/jobs", 3)
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        assert_eq!(report.upload_status(), UploadStatus::Succeeded);
+    }
+
+    #[test]
+    fn test_expand_lines_zero_line_count() {
+        let mut lines = HashMap::new();
+        lines.insert(3, 2);
+        assert_eq!(expand_lines(&lines, 0), vec![None, None, Some(2)]);
+        assert_eq!(expand_lines(&lines, 4), vec![None, None, Some(2), None]);
+    }
+
+    #[test]
+    fn test_expand_lines_huge_line_number() {
+        let mut lines = HashMap::new();
+        lines.insert(2, 1);
+        lines.insert(usize::MAX, 5);
+        lines.insert(MAX_EXPANDED_LINES + 1, 5);
+        assert_eq!(expand_lines(&lines, 3), vec![None, Some(1), None]);
+        assert_eq!(expand_lines(&lines, 0), vec![None, Some(1)]);
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let path =
+            env::temp_dir().join(format!("coveralls_api_report_{}.json", std::process::id()));
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_commit("abcdef");
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(1), None, Some(0)],
+            ..Default::default()
+        });
+
+        report.write_to_file(&path).unwrap();
+        let written = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        let written: serde_json::Value = serde_json::from_slice(&written.unwrap()).unwrap();
+        assert_eq!(written, serde_json::to_value(&report).unwrap());
+    }
+
+    #[test]
+    fn test_repo_token_redacted() {
+        let token = RepoToken::new("secret-token".to_string());
+        assert_eq!(format!("{:?}", token), "RepoToken(\"****\")");
+        assert_eq!(token.to_string(), "****");
+        assert_eq!(token.expose(), "secret-token");
+
+        let id = Identity::RepoToken(token);
+        assert!(!format!("{:?}", id).contains("secret-token"));
+        let json = serde_json::to_value(CoverallsReport::new(id)).unwrap();
+        assert_eq!(json["repo_token"], "secret-token");
+    }
+
+    #[test]
+    fn test_concourse_env() {
+        let _lock = lock_env();
+        env::set_var("BUILD_ID", "1021");
+        env::set_var("BUILD_NAME", "14");
+        env::set_var("BUILD_PIPELINE_NAME", "coverage");
+        env::set_var("BUILD_TEAM_NAME", "main");
+        env::set_var("BUILD_JOB_NAME", "test");
+        env::set_var("ATC_EXTERNAL_URL", "https://ci.example.com");
+
+        let service = Service::from_env_ordered(&[CiService::Concourse]);
+
+        env::remove_var("BUILD_ID");
+        env::remove_var("BUILD_NAME");
+        env::remove_var("BUILD_PIPELINE_NAME");
+        env::remove_var("BUILD_TEAM_NAME");
+        env::remove_var("BUILD_JOB_NAME");
+        env::remove_var("ATC_EXTERNAL_URL");
+
+        let service = service.unwrap();
+        assert_eq!(service.name, CiService::Concourse);
+        assert_eq!(service.job_id, Some("1021".to_string()));
+        assert_eq!(service.number, Some("14".to_string()));
+        assert_eq!(
+            service.build_url,
+            Some(
+                "https://ci.example.com/teams/main/pipelines/coverage/jobs/test/builds/14"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_head_from_commit() {
+        let head = Head::from_commit(
+            "abc123",
+            ("Author", "author@example.com"),
+            ("Committer", "committer@example.com"),
+            "Fix things",
+        );
+        assert_eq!(head.id, "abc123");
+        assert_eq!(head.author_name, "Author");
+        assert_eq!(head.author_email, "author@example.com");
+        assert_eq!(head.committer_name, "Committer");
+        assert_eq!(head.committer_email, "committer@example.com");
+        assert_eq!(head.message, "Fix things");
+    }
+
+    #[test]
+    fn test_clamp_hits() {
+        let mut source = Source {
+            coverage: vec![Some(usize::MAX), None, Some(0), Some(5)],
+            ..Default::default()
+        };
+        source.clamp_hits(1_000_000);
+        assert_eq!(
+            source.coverage,
+            vec![Some(1_000_000), None, Some(0), Some(5)]
+        );
+    }
+
+    #[test]
+    fn test_is_shallow() {
+        let repo = env::temp_dir().join(format!("coveralls_api_shallow_{}", std::process::id()));
+        let git_dir = repo.join(".git");
+        std::fs::create_dir_all(&git_dir).unwrap();
+        let before = GitInfo::is_shallow(&repo);
+        std::fs::write(git_dir.join("shallow"), "abc123\n").unwrap();
+        let after = GitInfo::is_shallow(&repo);
+        let git_dir_shallow = GitInfo::is_shallow(&git_dir);
+        let _ = std::fs::remove_dir_all(&repo);
+
+        assert!(!before);
+        assert!(after);
+        assert!(git_dir_shallow);
+    }
+
+    #[test]
+    fn test_git_info_from_env_shallow() {
+        let repo =
+            env::temp_dir().join(format!("coveralls_api_shallow_env_{}", std::process::id()));
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+
+        let _lock = lock_env();
+        env::set_var("GITHUB_SHA", "abc123");
+        env::set_var("GITHUB_WORKSPACE", &repo);
+        let full = GitInfo::from_env();
+        std::fs::write(repo.join(".git").join("shallow"), "abc123\n").unwrap();
+        let shallow = GitInfo::from_env();
+        env::remove_var("GITHUB_SHA");
+        env::remove_var("GITHUB_WORKSPACE");
+        let _ = std::fs::remove_dir_all(&repo);
+
+        assert!(!full.unwrap().shallow);
+        let shallow = shallow.unwrap();
+        assert!(shallow.shallow);
+        let json = serde_json::to_value(&shallow).unwrap();
+        assert!(json.get("shallow").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_auto_compression() {
+        let posted = Arc::new(Mutex::new(vec![]));
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_compression(Compression::Auto);
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: vec![],
+            posted: posted.clone(),
+        }));
+
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(
+            posted.lock().unwrap()[0],
+            serde_json::to_vec(&report).unwrap()
+        );
+
+        for i in 0..100 {
+            report.add_source(Source {
+                name: format!("src/file_{}.rs", i),
+                coverage: vec![Some(1); 10],
+                ..Default::default()
+            });
+        }
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(
+            posted.lock().unwrap()[1],
+            gzip(&report.to_json().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_set_pull_request() {
+        let mut service = Service::get_travis_env();
+        service.set_pull_request("12").unwrap();
+        assert_eq!(service.pull_request, Some("12".to_string()));
+
+        let res = service.set_pull_request("refs/pull/12/merge");
+        assert!(matches!(res, Err(CoverallsError::InvalidPullRequest(_))));
+        assert_eq!(service.pull_request, Some("12".to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_add_sources_parallel() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let files = ["tests/example/mysource.rs", "src/lib.rs", "Cargo.toml"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let mut lines = HashMap::new();
+                lines.insert(i + 1, i);
+                (PathBuf::from(name), root.join(name), lines, None)
+            })
+            .collect::<Vec<_>>();
+
+        let mut serial = CoverallsReport::new(Identity::RepoToken("token".into()));
+        for (repo_path, path, lines, branches) in &files {
+            serial.add_source(Source::new(repo_path, path, lines, branches, true).unwrap());
+        }
+        let mut parallel = CoverallsReport::new(Identity::RepoToken("token".into()));
+        parallel.add_sources_parallel(files, true).unwrap();
+        assert_eq!(parallel.source_files, serial.source_files);
+
+        let missing = vec![(
+            PathBuf::from("missing.rs"),
+            root.join("missing.rs"),
+            HashMap::new(),
+            None,
+        )];
+        assert!(parallel.add_sources_parallel(missing, false).is_err());
+        assert_eq!(parallel.source_files.len(), 3);
+    }
+
+    #[test]
+    fn test_branch_coverage_percent() {
+        let mut source = Source::default();
+        assert_eq!(source.branch_coverage_percent(), None);
+
+        let mut branches = BranchData::from_cobertura(3, 2, 1);
+        branches.extend(BranchData::from_cobertura(7, 2, 2));
+        source.branches = Some(expand_branches(&branches));
+        assert_eq!(source.branch_coverage_percent(), Some(75.0));
+    }
+
+    #[test]
+    fn test_strip_sources() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let source = Source::new(repo_path, &path, &HashMap::new(), &None, true).unwrap();
+        let digest = source.digest();
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(source);
+
+        report.strip_sources();
+        assert!(!report.source_files[0].has_embedded_source());
+        assert_eq!(report.source_files[0].digest(), digest);
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["source_files"][0].get("source").is_none());
+    }
+
+    #[test]
+    fn test_netlify_env() {
+        let _lock = lock_env();
+        env::set_var("NETLIFY", "true");
+        env::set_var("BUILD_ID", "5d4aeac2ccabf517d2f219b8");
+        env::set_var("BRANCH", "feature");
+        env::set_var("PULL_REQUEST", "true");
+        env::set_var("REVIEW_ID", "21");
+        env::set_var("COMMIT_REF", "abc123");
+
+        let service = Service::from_env_ordered(&[CiService::Netlify]);
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_commit_from_env();
+
+        env::remove_var("NETLIFY");
+        env::remove_var("BUILD_ID");
+        env::remove_var("BRANCH");
+        env::remove_var("PULL_REQUEST");
+        env::remove_var("REVIEW_ID");
+        env::remove_var("COMMIT_REF");
+
+        let service = service.unwrap();
+        assert_eq!(service.name, CiService::Netlify);
+        assert_eq!(service.job_id, Some("5d4aeac2ccabf517d2f219b8".to_string()));
+        assert_eq!(service.branch, Some("feature".to_string()));
+        assert_eq!(service.pull_request, Some("21".to_string()));
+        assert_eq!(report.commit(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_vercel_env() {
+        let _lock = lock_env();
+        env::set_var("VERCEL", "1");
+        env::set_var("VERCEL_URL", "my-app-abc123.vercel.app");
+        env::set_var("VERCEL_GIT_COMMIT_REF", "main");
+        env::set_var("VERCEL_GIT_PULL_REQUEST_ID", "");
+        env::set_var("VERCEL_GIT_COMMIT_SHA", "def456");
+
+        let service = Service::from_env_ordered(&[CiService::Vercel]);
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_commit_from_env();
+
+        env::remove_var("VERCEL");
+        env::remove_var("VERCEL_URL");
+        env::remove_var("VERCEL_GIT_COMMIT_REF");
+        env::remove_var("VERCEL_GIT_PULL_REQUEST_ID");
+        env::remove_var("VERCEL_GIT_COMMIT_SHA");
+
+        let service = service.unwrap();
+        assert_eq!(service.name, CiService::Vercel);
+        assert_eq!(
+            service.build_url,
+            Some("https://my-app-abc123.vercel.app".to_string())
+        );
+        assert_eq!(service.branch, Some("main".to_string()));
+        assert_eq!(service.pull_request, None);
+        assert_eq!(report.commit(), Some("def456"));
+    }
+
+    #[test]
+    #[cfg(any(feature = "rustls-tls", feature = "native-tls"))]
+    fn test_check_tls_support() {
+        assert!(CoverallsReport::check_tls_support().is_ok());
+    }
+
+    #[test]
+    fn test_add_hits() {
+        let mut source = Source {
+            coverage: vec![None, Some(0), None],
+            ..Default::default()
         };
+        source.add_hit(2);
+        source.add_hit(2);
+        source.add_hits(2, 3);
+        source.add_hit(1);
+        source.add_hits(5, 2);
+        source.add_hit(0);
+        assert_eq!(source.coverage, vec![Some(1), Some(5), None, None, Some(2)]);
+    }
 
-        Ok(())
+    #[test]
+    fn test_set_branch() {
+        let service = Service {
+            name: CiService::Jenkins,
+            job_id: None,
+            number: None,
+            build_url: None,
+            branch: Some("HEAD".to_string()),
+            pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        };
+        let mut report =
+            CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), service));
+        report.set_detailed_git_info(GitInfo {
+            head: Head::default(),
+            branch: "HEAD".to_string(),
+            remotes: vec![],
+            shallow: false,
+        });
+
+        report.set_branch("release");
+        assert_eq!(report.git().unwrap().branch, "release");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_branch"], "release");
+        assert_eq!(json["git"]["branch"], "release");
     }
 
-    pub fn upload_status(&mut self) -> UploadStatus {
-        self.last_status
+    #[test]
+    fn test_summary() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_commit("abcdef1234567890");
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(1), None, Some(0), Some(2)],
+            ..Default::default()
+        });
+        report.add_source(Source {
+            name: "src/main.rs".to_string(),
+            coverage: vec![Some(0)],
+            ..Default::default()
+        });
+
+        let summary = report.summary();
+        assert!(summary.contains("Sources: 2"));
+        assert!(summary.contains("Line coverage: 50.00% (2/4)"));
+        assert!(summary.contains("CI: none"));
+        assert!(summary.contains("Commit: abcdef1"));
+        assert!(summary.contains("Embedded source: no"));
     }
-}
 
-impl Serialize for CoverallsReport {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let size = 1 + match self.id {
-            Identity::RepoToken(_) => 1 + self.commit.is_some() as usize,
-            Identity::ServiceToken(_, _) => 2 + self.commit.is_some() as usize,
+    #[test]
+    fn test_digest_algorithm() {
+        assert_eq!(
+            DigestAlgorithm::Md5.digest(b"abc"),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        #[cfg(feature = "blake3")]
+        assert_eq!(
+            DigestAlgorithm::Blake3.digest(b"abc"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn test_replace_source() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(0)],
+            ..Default::default()
+        });
+
+        let old = report.replace_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(3)],
+            ..Default::default()
+        });
+        assert_eq!(old.unwrap().coverage, vec![Some(0)]);
+        assert_eq!(report.source_files.len(), 1);
+        assert_eq!(report.source_files[0].coverage, vec![Some(3)]);
+
+        let old = report.replace_source(Source {
+            name: "src/main.rs".to_string(),
+            ..Default::default()
+        });
+        assert!(old.is_none());
+        assert_eq!(report.source_files.len(), 2);
+    }
+
+    #[test]
+    fn test_github_event_pull_request() {
+        let event = env::temp_dir().join(format!(
+            "coveralls_api_pr_event_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &event,
+            r#"{"action": "synchronize", "pull_request": {"number": 73}}"#,
+        )
+        .unwrap();
+
+        let _lock = lock_env();
+        env::set_var("GITHUB_RUN_ID", "1234");
+        env::set_var("GITHUB_REF", "refs/heads/feature");
+        env::set_var("GITHUB_EVENT_PATH", &event);
+
+        let service = Service::get_github_actions_env();
+
+        env::remove_var("GITHUB_RUN_ID");
+        env::remove_var("GITHUB_REF");
+        env::remove_var("GITHUB_EVENT_PATH");
+        let _ = std::fs::remove_file(&event);
+
+        assert_eq!(service.pull_request, Some("73".to_string()));
+    }
+
+    #[test]
+    fn test_set_service_name() {
+        let service = Service {
+            name: CiService::Circle,
+            job_id: Some("7".to_string()),
+            number: None,
+            build_url: None,
+            branch: None,
+            pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         };
-        let mut s = serializer.serialize_struct("CoverallsReport", size)?;
-        match self.id {
-            Identity::RepoToken(ref r) => {
-                s.serialize_field("repo_token", &r)?;
+        let mut report =
+            CoverallsReport::new(Identity::ServiceToken(RepoToken::default(), service));
+        report.set_service_name(CiService::Other("coveralls-ruby".to_string()));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_name"], "coveralls-ruby");
+        assert_eq!(json["service_job_id"], "7");
+
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_service_name(CiService::Jenkins);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_name"], "jenkins");
+        assert_eq!(json["repo_token"], "token");
+    }
+
+    #[test]
+    fn test_upload_status_into_result() {
+        let res: Result<(), CoverallsError> = UploadStatus::Succeeded.into();
+        assert!(res.is_ok());
+        let res: Result<(), CoverallsError> = UploadStatus::Failed(422).into();
+        assert!(matches!(res, Err(CoverallsError::UploadFailed(422, None))));
+        let res: Result<(), CoverallsError> = UploadStatus::Pending.into();
+        assert!(matches!(
+            res,
+            Err(CoverallsError::UploadIncomplete(UploadStatus::Pending))
+        ));
+        let res: Result<(), CoverallsError> = UploadStatus::Unknown.into();
+        assert!(matches!(
+            res,
+            Err(CoverallsError::UploadIncomplete(UploadStatus::Unknown))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_wait_for_completion() {
+        struct Processing {
+            polls: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl Transport for Processing {
+            fn send(
+                &self,
+                _: &str,
+                _: Vec<u8>,
+                _: &str,
+                _: bool,
+            ) -> Result<(u32, Vec<u8>), CoverallsError> {
+                Ok((200, br#"{"url":"https://coveralls.io/jobs/1"}"#.to_vec()))
             }
-            Identity::ServiceToken(ref r, ref serv) => {
-                if !r.is_empty() {
-                    s.serialize_field("repo_token", &r)?;
-                }
-                s.serialize_field("service_name", serv.name.value())?;
-                if let Some(ref id) = serv.job_id {
-                    s.serialize_field("service_job_id", id)?;
-                }
-                if let Some(ref num) = serv.number {
-                    s.serialize_field("service_number", &num)?;
-                }
-                if let Some(ref url) = serv.build_url {
-                    s.serialize_field("service_build_url", &url)?;
-                }
-                if let Some(ref branch) = serv.branch {
-                    s.serialize_field("service_branch", &branch)?;
-                }
-                if let Some(ref pr) = serv.pull_request {
-                    s.serialize_field("service_pull_request", &pr)?;
+
+            fn get(&self, url: &str) -> Result<(u32, Vec<u8>), CoverallsError> {
+                let mut polls = self.polls.lock().unwrap();
+                polls.push(url.to_string());
+                match polls.len() {
+                    1 => Ok((404, vec![])),
+                    2 => Ok((401, vec![])),
+                    3 => Ok((200, br#"{"covered_percent":null}"#.to_vec())),
+                    _ => Ok((200, br#"{"covered_percent":85.5}"#.to_vec())),
                 }
             }
         }
-        if let Some(ref sha) = self.commit {
-            s.serialize_field("commit_sha", &sha)?;
+
+        let polls = Arc::new(Mutex::new(vec![]));
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(Processing {
+            polls: polls.clone(),
+        }));
+        // Nothing to wait for before an upload
+        let status = report.wait_for_completion(Duration::from_secs(5), Duration::from_millis(10));
+        assert_eq!(status, UploadStatus::Pending);
+        assert!(polls.lock().unwrap().is_empty());
+
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        let start = Instant::now();
+        let status = report.wait_for_completion(Duration::from_secs(5), Duration::from_millis(10));
+        assert_eq!(status, UploadStatus::Succeeded);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let polls = polls.lock().unwrap();
+        assert_eq!(polls.len(), 4);
+        assert!(polls
+            .iter()
+            .all(|u| u == "https://coveralls.io/jobs/1.json"));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_wait_for_completion_timeout() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: br#"{"url":"https://coveralls.io/jobs/2"}"#.to_vec(),
+            posted: Arc::new(Mutex::new(vec![])),
+        }));
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        let status =
+            report.wait_for_completion(Duration::from_millis(50), Duration::from_millis(10));
+        assert_eq!(status, UploadStatus::Pending);
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_wait_for_completion_without_polling() {
+        struct PostOnly;
+
+        impl Transport for PostOnly {
+            fn send(
+                &self,
+                _: &str,
+                _: Vec<u8>,
+                _: &str,
+                _: bool,
+            ) -> Result<(u32, Vec<u8>), CoverallsError> {
+                Ok((200, br#"{"url":"https://coveralls.io/jobs/3"}"#.to_vec()))
+            }
         }
-        if let Some(ref git) = self.git {
-            s.serialize_field("git", &git)?;
+
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_transport(Box::new(PostOnly));
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        let start = Instant::now();
+        let status = report.wait_for_completion(Duration::from_secs(5), Duration::from_millis(10));
+        assert_eq!(status, UploadStatus::Succeeded);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_add_query_param() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let url = "https://coveralls.example.com/api/v1/jobs";
+        assert_eq!(report.endpoint_url(url).unwrap(), url);
+
+        report.add_query_param("token", "a b&c");
+        report.add_query_param("job", "7");
+        assert_eq!(
+            report.endpoint_url(url).unwrap(),
+            "https://coveralls.example.com/api/v1/jobs?token=a+b%26c&job=7"
+        );
+        assert!(matches!(
+            report.endpoint_url("not a url"),
+            Err(CoverallsError::InvalidUrl(_))
+        ));
+
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        report.send_to_endpoint(&url).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v1/jobs?token=a+b%26c&job=7 HTTP/1.1"));
+    }
+
+    #[test]
+    fn test_normalize_branches() {
+        let branch = |line_number, branch_number, hits| BranchData {
+            line_number,
+            block_name: 0,
+            branch_number,
+            hits,
+        };
+        let mut source = Source {
+            branches: Some(expand_branches(&vec![
+                branch(9, 1, 0),
+                branch(3, 0, 2),
+                branch(9, 0, 1),
+                branch(3, 0, 2),
+            ])),
+            ..Default::default()
+        };
+        source.normalize_branches();
+        assert_eq!(
+            source.branch_data(),
+            Some(vec![branch(3, 0, 2), branch(9, 0, 1), branch(9, 1, 0)])
+        );
+
+        let mut source = Source::default();
+        source.normalize_branches();
+        assert_eq!(source.branch_data(), None);
+    }
+
+    #[test]
+    fn test_github_oidc_serialization() {
+        let service = Service {
+            name: CiService::GithubActions,
+            job_id: Some("7".to_string()),
+            number: None,
+            build_url: None,
+            branch: None,
+            pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
+        };
+        let id = Identity::GithubOidc("header.payload.signature".to_string(), service);
+        assert!(format!("{:?}", id).starts_with("GithubOidc(\"****\", Service {"));
+        assert!(!format!("{:?}", id).contains("signature"));
+        let mut report = CoverallsReport::new(id);
+        report.set_service_number("42");
+        report.set_branch("main");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["service_name"], "github");
+        assert_eq!(json["oidc_token"], "header.payload.signature");
+        assert_eq!(json["service_job_id"], "7");
+        assert_eq!(json["service_number"], "42");
+        assert_eq!(json["service_branch"], "main");
+        assert!(json.get("repo_token").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_from_github_oidc() {
+        let (url, server) =
+            serve_once("HTTP/1.1 200 OK\r\nContent-Length: 21\r\n\r\n{\"value\":\"jwt-token\"}");
+        let _lock = lock_env();
+        env::remove_var("ACTIONS_ID_TOKEN_REQUEST_URL");
+        let options = HttpOptions::default();
+        assert!(Identity::from_github_oidc("coveralls", &options)
+            .unwrap()
+            .is_none());
+
+        env::set_var(
+            "ACTIONS_ID_TOKEN_REQUEST_URL",
+            format!("{}?api-version=2.0", url),
+        );
+        env::set_var("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "request-token");
+        let mut options = HttpOptions::default();
+        options
+            .headers
+            .push(("X-Proxy-Token".to_string(), "12345".to_string()));
+        let id = Identity::from_github_oidc("coveralls", &options);
+        env::remove_var("ACTIONS_ID_TOKEN_REQUEST_URL");
+        env::remove_var("ACTIONS_ID_TOKEN_REQUEST_TOKEN");
+
+        match id.unwrap() {
+            Some(Identity::GithubOidc(token, service)) => {
+                assert_eq!(token, "jwt-token");
+                assert_eq!(service.name, CiService::GithubActions);
+            }
+            id => panic!("unexpected identity {:?}", id),
         }
-        s.serialize_field("source_files", &self.source_files)?;
-        s.end()
+        let request = server.join().unwrap();
+        assert!(request.to_lowercase().contains("x-proxy-token: 12345"));
+
+        let (url, server) = serve_once(
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 23\r\n\r\n{\"message\":\"forbidden\"}",
+        );
+        env::set_var("ACTIONS_ID_TOKEN_REQUEST_URL", &url);
+        env::set_var("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "request-token");
+        let res = Identity::from_github_oidc("coveralls", &options);
+        env::remove_var("ACTIONS_ID_TOKEN_REQUEST_URL");
+        env::remove_var("ACTIONS_ID_TOKEN_REQUEST_TOKEN");
+        server.join().unwrap();
+        match res {
+            Err(CoverallsError::OidcTokenFailed(403, Some(msg))) => assert_eq!(msg, "forbidden"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(request.starts_with("GET /api/v1/jobs?api-version=2.0&audience=coveralls "));
+        assert!(request
+            .to_lowercase()
+            .contains("authorization: bearer request-token"));
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn test_new_with_line_count() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let mut lines = HashMap::new();
+        lines.insert(2, 1);
 
-    use crate::*;
-    use std::collections::HashMap;
+        let source =
+            Source::new_with_line_count(repo_path, &path, &lines, &None, false, 10).unwrap();
+        assert_eq!(source.line_count(), 10);
+        assert_eq!(source.coverage[1], Some(1));
+
+        let counted = Source::new(repo_path, &path, &lines, &None, false).unwrap();
+        assert_eq!(source.digest(), counted.digest());
+    }
 
     #[test]
-    fn test_expand_lines() {
-        let line_count = 10;
-        let mut example: HashMap<usize, usize> = HashMap::new();
-        example.insert(5, 1);
-        example.insert(6, 1);
-        example.insert(8, 2);
+    fn test_diff_serialization() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("diff").is_none());
 
-        let expected = vec![
-            None,
-            None,
-            None,
-            None,
-            Some(1),
-            Some(1),
-            None,
-            Some(2),
-            None,
-            None,
+        let diff = "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-old\n+new\n";
+        report.set_diff(diff.to_string());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["diff"], diff);
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_gzip_round_trip() {
+        let data = br#"{"repo_token":"token","source_files":[]}"#;
+        let decompress = |gz: &[u8]| {
+            let mut out = vec![];
+            GzDecoder::new(gz).read_to_end(&mut out).unwrap();
+            out
+        };
+        assert_eq!(decompress(&gzip(data).unwrap()), data);
+        assert_eq!(decompress(&deflate::deflate_bytes_gzip(data)), data);
+    }
+
+    #[test]
+    fn test_remote_provider() {
+        let provider = |url: &str| {
+            Remote {
+                name: "origin".to_string(),
+                url: url.to_string(),
+            }
+            .provider()
+        };
+        let cases = [
+            (
+                "https://github.com/xd009642/coveralls-api",
+                Some(RepoProvider::GitHub),
+            ),
+            (
+                "git@github.com:xd009642/coveralls-api.git",
+                Some(RepoProvider::GitHub),
+            ),
+            (
+                "ssh://git@github.com/xd009642/coveralls-api.git",
+                Some(RepoProvider::GitHub),
+            ),
+            (
+                "https://gitlab.com/group/project.git",
+                Some(RepoProvider::GitLab),
+            ),
+            (
+                "git@gitlab.com:group/project.git",
+                Some(RepoProvider::GitLab),
+            ),
+            (
+                "https://user@bitbucket.org/team/repo.git",
+                Some(RepoProvider::Bitbucket),
+            ),
+            (
+                "git@bitbucket.org:team/repo.git",
+                Some(RepoProvider::Bitbucket),
+            ),
+            ("https://git.example.com/team/repo.git", None),
+            ("git@example.com:github.com/repo.git", None),
         ];
+        for (url, expected) in cases.iter() {
+            assert_eq!(provider(url), *expected, "{}", url);
+        }
+    }
 
-        assert_eq!(expand_lines(&example, line_count), expected);
+    #[test]
+    fn test_truncate_message() {
+        let mut head = Head {
+            message: "é".repeat(10),
+            ..Default::default()
+        };
+        head.truncate_message(20);
+        assert_eq!(head.message, "é".repeat(10));
+        head.truncate_message(4);
+        assert_eq!(head.message, "éééé");
+        head.truncate_message(0);
+        assert_eq!(head.message, "");
     }
 
     #[test]
-    fn test_branch_expand() {
-        let b1 = BranchData {
-            line_number: 3,
-            block_name: 1,
-            branch_number: 1,
-            hits: 1,
+    #[cfg(feature = "encoding")]
+    fn test_new_with_encoding_latin1() {
+        let path = env::temp_dir().join(format!("coveralls_api_latin1_{}.rs", std::process::id()));
+        // "// café\nfn main() {}\n" in latin-1, 0xE9 isn't valid UTF-8 on its own
+        let raw = b"// caf\xe9\nfn main() {}\n";
+        std::fs::write(&path, raw).unwrap();
+
+        let mut lines = HashMap::new();
+        lines.insert(2, 1);
+        let utf8 = Source::new(Path::new("src/main.rs"), &path, &lines, &None, true);
+        let source = Source::new_with_encoding(
+            Path::new("src/main.rs"),
+            &path,
+            &lines,
+            &None,
+            true,
+            encoding_rs::WINDOWS_1252,
+        );
+        let _ = std::fs::remove_file(&path);
+
+        assert!(utf8.is_err());
+        let source = source.unwrap();
+        assert_eq!(source.source.as_deref(), Some("// café\nfn main() {}\n"));
+        assert_eq!(source.coverage, vec![None, Some(1)]);
+        assert_eq!(source.source_digest, md5::compute(&raw[..]).0);
+    }
+
+    #[test]
+    fn test_auth_kind() {
+        let service = Service {
+            name: CiService::Travis,
+            job_id: Some("1".to_string()),
+            number: None,
+            build_url: None,
+            branch: None,
+            pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         };
-        let b2 = BranchData {
-            line_number: 4,
-            block_name: 1,
-            branch_number: 2,
-            hits: 0,
+        assert_eq!(
+            Identity::RepoToken("token".into()).auth_kind(),
+            AuthKind::Token
+        );
+        assert_eq!(
+            Identity::ServiceToken(RepoToken::default(), service.clone()).auth_kind(),
+            AuthKind::Service
+        );
+        assert_eq!(
+            Identity::ServiceToken("token".into(), service.clone()).auth_kind(),
+            AuthKind::ServiceWithToken
+        );
+        assert_eq!(
+            Identity::GithubOidc("jwt".to_string(), service).auth_kind(),
+            AuthKind::Oidc
+        );
+    }
+
+    #[test]
+    fn test_service_number_overrides() {
+        let _lock = lock_env();
+        env::set_var("CI_JOB_ID", "100");
+        env::set_var("CI_PIPELINE_ID", "7");
+        env::set_var("CI_BUILD_NUMBER", "8");
+        env::set_var("COVERALLS_SERVICE_NUMBER", "pipeline-42");
+        let gitlab_number = Service::get_gitlab_env();
+        let generic_number = Service::get_generic_env();
+        env::set_var("COVERALLS_SERVICE_JOB_ID", "job-5");
+        let gitlab_job = Service::get_gitlab_env();
+        let generic_job = Service::get_generic_env();
+        for v in &[
+            "CI_JOB_ID",
+            "CI_PIPELINE_ID",
+            "CI_BUILD_NUMBER",
+            "COVERALLS_SERVICE_NUMBER",
+            "COVERALLS_SERVICE_JOB_ID",
+        ] {
+            env::remove_var(v);
+        }
+
+        assert_eq!(gitlab_number.number, Some("pipeline-42".to_string()));
+        assert_eq!(gitlab_number.job_id, Some("100".to_string()));
+        let generic_number = generic_number.unwrap();
+        assert_eq!(generic_number.number, Some("pipeline-42".to_string()));
+        assert_eq!(generic_number.job_id, Some("100".to_string()));
+        assert_eq!(gitlab_job.job_id, Some("job-5".to_string()));
+        assert_eq!(generic_job.unwrap().job_id, Some("job-5".to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_max_response_bytes() {
+        let (url, server) =
+            serve_once("HTTP/1.1 200 OK\r\nContent-Length: 26\r\n\r\nabcdefghijklmnopqrstuvwxyz");
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_max_response_bytes(10);
+        let (code, response) = report.send_to_endpoint_raw(&url).unwrap();
+        server.join().unwrap();
+        assert_eq!(code, 200);
+        assert_eq!(response, b"abcdefghij");
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_report_batch() {
+        let posted = Arc::new(Mutex::new(vec![]));
+        let report = |status| {
+            let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+            report.set_compression(Compression::Uncompressed);
+            report.set_transport(Box::new(MockTransport {
+                status,
+                response: vec![],
+                posted: posted.clone(),
+            }));
+            report
         };
+        let mut batch = ReportBatch::new();
+        assert!(batch.is_empty());
+        batch.add_report("abc123", report(200));
+        batch.add_report("def456", report(422));
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch.commits().collect::<Vec<_>>(),
+            vec!["abc123", "def456"]
+        );
+        assert_eq!(batch.report("abc123").unwrap().commit(), Some("abc123"));
 
-        let v = vec![b1, b2];
-        let actual = expand_branches(&v);
-        let expected = vec![3, 1, 1, 1, 4, 1, 2, 0];
-        assert_eq!(actual, expected);
+        let statuses = batch.upload("http://localhost/jobs");
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(
+            statuses["abc123"].as_ref().unwrap(),
+            &UploadStatus::Succeeded
+        );
+        assert_eq!(
+            statuses["def456"].as_ref().unwrap(),
+            &UploadStatus::Failed(422)
+        );
+
+        let statuses = batch.upload_concurrent("http://localhost/jobs");
+        assert_eq!(
+            statuses["abc123"].as_ref().unwrap(),
+            &UploadStatus::Succeeded
+        );
+        assert_eq!(
+            statuses["def456"].as_ref().unwrap(),
+            &UploadStatus::Failed(422)
+        );
+
+        let posted = posted.lock().unwrap();
+        assert_eq!(posted.len(), 4);
+        let commits = posted
+            .iter()
+            .map(|p| serde_json::from_slice::<serde_json::Value>(p).unwrap()["commit_sha"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(commits[0], "abc123");
+        assert_eq!(commits[1], "def456");
+    }
+
+    #[test]
+    fn test_find_repo_root() {
+        let root = env::temp_dir().join(format!("coveralls_api_repo_root_{}", std::process::id()));
+        let nested = root.join("src").join("module");
+        let submodule = root.join("vendor").join("dep");
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(submodule.join("src")).unwrap();
+        std::fs::write(submodule.join(".git"), "gitdir: ../../.git/modules/dep\n").unwrap();
+        std::fs::write(nested.join("lib.rs"), "fn main() {}\n").unwrap();
+
+        let from_file = find_repo_root(&nested.join("lib.rs"));
+        let from_dir = find_repo_root(&nested);
+        let from_root = find_repo_root(&root);
+        let from_submodule = find_repo_root(&submodule.join("src"));
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(from_file, Some(root.clone()));
+        assert_eq!(from_dir, Some(root.clone()));
+        assert_eq!(from_root, Some(root));
+        assert_eq!(from_submodule, Some(submodule));
+        assert_eq!(find_repo_root(Path::new("/")), None);
+    }
+
+    #[test]
+    fn test_set_commit_and_git() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        let git = GitInfo {
+            head: Head::from_commit("abc123", ("A", "a@b.c"), ("A", "a@b.c"), "msg"),
+            branch: "master".to_string(),
+            remotes: vec![],
+            shallow: false,
+        };
+        report.set_commit_and_git("abc123", git.clone());
+        assert_eq!(report.commit(), Some("abc123"));
+        assert_eq!(report.git(), Some(&git));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["commit_sha"], "abc123");
+        assert_eq!(json["git"]["head"]["id"], "abc123");
+        assert_eq!(json["git"]["branch"], "master");
+    }
+
+    #[test]
+    fn test_source_hit_past_end_of_file() {
+        let repo_path = Path::new("tests/example/mysource.rs");
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(repo_path);
+        let mut lines = HashMap::new();
+        lines.insert(6, 1);
+        lines.insert(9, 3);
+        let source = Source::new(repo_path, &path, &lines, &None, false).unwrap();
+        assert_eq!(source.coverage.len(), 9);
+        assert_eq!(source.coverage[5], Some(1));
+        assert_eq!(source.coverage[7], None);
+        assert_eq!(source.coverage[8], Some(3));
+    }
+
+    #[test]
+    #[cfg(feature = "upload")]
+    fn test_save_to_file() {
+        let path = env::temp_dir().join(format!("coveralls_api_payload_{}.gz", std::process::id()));
+        let posted = Arc::new(Mutex::new(vec![]));
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        report.set_commit("abcdef");
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(1), None, Some(0)],
+            ..Default::default()
+        });
+
+        let saved = report.save_to_file(&path).map(|_| std::fs::read(&path));
+        let _ = std::fs::remove_file(&path);
+        let saved = saved.unwrap().unwrap();
+
+        let mut json = vec![];
+        GzDecoder::new(&saved[..]).read_to_end(&mut json).unwrap();
+        assert_eq!(json, report.to_json().unwrap());
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["commit_sha"], "abcdef");
+
+        report.set_transport(Box::new(MockTransport {
+            status: 200,
+            response: vec![],
+            posted: posted.clone(),
+        }));
+        report.send_to_endpoint("http://localhost/jobs").unwrap();
+        assert_eq!(posted.lock().unwrap()[0], saved);
+    }
+
+    #[test]
+    fn test_sanitize_message() {
+        let mut head = Head {
+            message: "Fix\u{1b}[31m colours\r\n\n\tDetails\u{0}".to_string(),
+            ..Default::default()
+        };
+        head.sanitize_message();
+        assert_eq!(
+            head.message,
+            "Fix\u{FFFD}[31m colours\r\n\n\tDetails\u{FFFD}"
+        );
+
+        head.set_message_bytes(b"caf\xe9\x07 bar");
+        assert_eq!(head.message, "caf\u{FFFD}\u{FFFD} bar");
+        let json = serde_json::to_string(&head).unwrap();
+        let parsed: Head = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, head);
+    }
+
+    #[test]
+    fn test_has_branch_coverage() {
+        let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+        assert!(!report.has_branch_coverage());
+        report.add_source(Source {
+            name: "src/lib.rs".to_string(),
+            coverage: vec![Some(1)],
+            ..Default::default()
+        });
+        report.add_source(Source {
+            name: "src/main.rs".to_string(),
+            branches: Some(vec![]),
+            ..Default::default()
+        });
+        assert!(!report.has_branch_coverage());
+        report.add_source(Source {
+            name: "src/util.rs".to_string(),
+            branches: Some(expand_branches(&BranchData::from_cobertura(1, 2, 1))),
+            ..Default::default()
+        });
+        assert!(report.has_branch_coverage());
     }
 }
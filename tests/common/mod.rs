@@ -0,0 +1,64 @@
+//! HTTP fixtures shared by the integration tests and the unit tests in
+//! `src/lib.rs`, not every test uses all of them
+#![allow(dead_code)]
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::thread;
+
+/// Reads a HTTP request including its body from `stream`
+pub fn read_request<S: Read>(stream: &mut S) -> String {
+    let mut request = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf).unwrap();
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request).to_string();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end]
+                .lines()
+                .filter_map(|l| l.split_once(':'))
+                .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                break;
+            }
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&request).to_string()
+}
+
+/// Starts a server on localhost which replies to `count` requests with
+/// `response`. Returns the URL jobs are uploaded to and a handle which gives
+/// the raw requests received.
+pub fn serve(count: usize, response: &'static str) -> (String, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api/v1/jobs", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        (0..count)
+            .map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = read_request(&mut stream);
+                stream.write_all(response.as_bytes()).unwrap();
+                request
+            })
+            .collect()
+    });
+    (url, handle)
+}
+
+/// Like `serve` for a single request, the handle gives the raw request
+pub fn serve_once(response: &'static str) -> (String, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api/v1/jobs", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_request(&mut stream);
+        stream.write_all(response.as_bytes()).unwrap();
+        request
+    });
+    (url, handle)
+}
@@ -1,3 +1,4 @@
+#![cfg(feature = "upload")]
 extern crate coveralls_api;
 extern crate serde_json;
 use coveralls_api::*;
@@ -45,10 +46,13 @@ fn test_submission() {
             build_url: None,
             branch: None,
             pull_request: None,
+            base_branch: None,
+            flag_name: None,
+            attempt: None,
         };
-        Identity::ServiceToken(String::new(), serv)
+        Identity::ServiceToken(RepoToken::default(), serv)
     } else {
-        Identity::RepoToken(secret_key)
+        Identity::RepoToken(RepoToken::new(secret_key))
     };
     let mut report = CoverallsReport::new(id);
     report.add_source(source);
@@ -0,0 +1,24 @@
+extern crate coveralls_api;
+extern crate serde_json;
+use coveralls_api::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Builds and serializes a report using only the data model so it compiles
+/// with `--no-default-features`
+#[test]
+fn test_model_only() {
+    let repo_path = Path::new("tests/example/mysource.rs");
+    let mut lines: HashMap<usize, usize> = HashMap::new();
+    lines.insert(2, 1);
+
+    let source = Source::new(repo_path, repo_path, &lines, &None, false).unwrap();
+    let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+    report.add_source(source);
+    report.set_commit("abc123");
+
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["repo_token"], "token");
+    assert_eq!(json["commit_sha"], "abc123");
+    assert_eq!(json["source_files"][0]["name"], "tests/example/mysource.rs");
+}
@@ -0,0 +1,46 @@
+#![cfg(feature = "upload")]
+extern crate coveralls_api;
+use coveralls_api::*;
+
+mod common;
+
+#[test]
+fn test_run_parallel_upload() {
+    let (url, server) = common::serve(
+        4,
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n{}",
+    );
+    let reports = (0..3)
+        .map(|i| {
+            let mut report = CoverallsReport::new(Identity::RepoToken("token".into()));
+            report.add_source(
+                Source::new(
+                    std::path::Path::new(&format!("src/file_{}.rs", i)),
+                    std::path::Path::new("tests/example/mysource.rs"),
+                    &Default::default(),
+                    &None,
+                    false,
+                )
+                .unwrap(),
+            );
+            report
+        })
+        .collect::<Vec<_>>();
+
+    let status = run_parallel_upload(reports, "42", &url).unwrap();
+    assert_eq!(status, UploadStatus::Succeeded);
+
+    let requests = server
+        .join()
+        .unwrap()
+        .iter()
+        .map(|r| r.lines().next().unwrap_or("").to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(requests.len(), 4);
+    let jobs = requests
+        .iter()
+        .filter(|r| r.starts_with("POST /api/v1/jobs "))
+        .count();
+    assert_eq!(jobs, 3);
+    assert_eq!(requests[3], "POST /webhook HTTP/1.1");
+}